@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use crate::configuration::Environment;
+
+/// Automated checks on mails, typically mails produced by other automations.
+///
+/// Everything about what to check is in the config file; see the README.
+#[derive(Debug, clap::Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file to use; defaults to settings/<environment>.json5
+    #[arg(long, global = true, env = "AMCHECK_CONFIG_FILE", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Which environment we're running in; `test` skips TLS verification, so don't use it for
+    /// anything real
+    #[arg(
+        long,
+        global = true,
+        env = "AMCHECK_ENVIRONMENT",
+        default_value = "prod"
+    )]
+    pub environment: Environment,
+
+    /// Go through the motions, but don't move or delete any mail
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
+    /// Pretend it's this time (RFC 3339, e.g. 2024-03-12T09:00:00Z) for everything that depends on
+    /// the date, instead of the actual current time
+    #[arg(long, global = true, env = "AMCHECK_NOW", value_name = "TIMESTAMP", value_parser = parse_now)]
+    pub now: Option<time::OffsetDateTime>,
+
+    /// Only run the handler with this name; can be given more than once
+    #[arg(long = "handler", global = true, value_name = "NAME")]
+    pub handlers: Vec<String>,
+
+    /// How log lines are written
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+
+    /// Log more; once for debug, twice for trace (which includes all IMAP traffic).  Overrides
+    /// RUST_LOG.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::Subcommand)]
+pub enum Command {
+    /// Move the mails that the handlers match from the source folders into storage
+    Move,
+    /// Walk each handler's checker tree over the mails in storage
+    Check,
+    /// Move and then check, in a single IMAP session
+    Run,
+    /// Check the config file for mistakes, without connecting to anything
+    Validate,
+    /// Print a JSON Schema for the config file, for editors and config generators
+    Schema,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Multi-line, human-friendly output
+    Pretty,
+    /// One line per event
+    Compact,
+    /// logfmt key=value lines, for feeding to log collectors
+    Logfmt,
+}
+
+fn parse_now(s: &str) -> Result<time::OffsetDateTime, String> {
+    time::OffsetDateTime::parse(s, &time::format_description::well_known::Rfc3339)
+        .map_err(|e| format!("{e}; it should look like 2024-03-12T09:00:00Z"))
+}
@@ -0,0 +1,128 @@
+// What time it is, and what time zone local times (in `since`, cron schedules and so on) are in.
+//
+// This is worked out once at startup, before any threads, so that every date calculation in a run
+// agrees with every other one, and so that `--now` can pin it for reproducible runs.
+
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+use time_tz::{OffsetDateTimeExt, PrimitiveDateTimeExt, Tz};
+
+/// A time zone from the tz database, by its name, e.g. "Europe/London" or "America/Los_Angeles".
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeZone(#[schemars(with = "String")] &'static Tz);
+
+impl TryFrom<String> for TimeZone {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        time_tz::timezones::get_by_name(&s)
+            .map(TimeZone)
+            .ok_or_else(|| format!("'{s}' isn't a time zone name like \"Europe/London\""))
+    }
+}
+
+impl From<TimeZone> for String {
+    fn from(zone: TimeZone) -> Self {
+        time_tz::TimeZone::name(zone.0).to_string()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Zone {
+    Fixed(UtcOffset),
+    Named(&'static Tz),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Clock {
+    now: OffsetDateTime,
+    zone: Zone,
+}
+
+impl Clock {
+    /// A clock stopped at `now` (or the actual current time), in `zone`.  Without a zone, local
+    /// times are in `now`'s offset if it was given, or the system's current offset if not, which
+    /// the time crate refuses to work out once the process is multi-threaded, and can't work out
+    /// at all in some containers.
+    pub fn new(
+        now: Option<OffsetDateTime>,
+        zone: Option<TimeZone>,
+    ) -> Result<Clock, time::error::IndeterminateOffset> {
+        Ok(match (now, zone) {
+            (now, Some(TimeZone(tz))) => Clock {
+                now: now.unwrap_or_else(OffsetDateTime::now_utc).to_timezone(tz),
+                zone: Zone::Named(tz),
+            },
+            (Some(now), None) => Clock::fixed(now),
+            (None, None) => Clock::fixed(OffsetDateTime::now_local()?),
+        })
+    }
+
+    /// A clock stopped at `now`, with local times in `now`'s offset.
+    pub fn fixed(now: OffsetDateTime) -> Clock {
+        Clock {
+            now,
+            zone: Zone::Fixed(now.offset()),
+        }
+    }
+
+    /// The current time, in the local time zone.
+    pub fn now(&self) -> OffsetDateTime {
+        self.now
+    }
+
+    /// The given time, in the local time zone.
+    pub fn to_local(&self, datetime: OffsetDateTime) -> OffsetDateTime {
+        match self.zone {
+            Zone::Fixed(offset) => datetime.to_offset(offset),
+            Zone::Named(tz) => datetime.to_timezone(tz),
+        }
+    }
+
+    /// The given local time.  When the clocks go back, a local time that happens twice is the
+    /// first of them; when they go forward, one that doesn't happen at all is an hour or so
+    /// either side of the gap.
+    pub fn local(&self, datetime: PrimitiveDateTime) -> OffsetDateTime {
+        match self.zone {
+            Zone::Fixed(offset) => datetime.assume_offset(offset),
+            Zone::Named(tz) => match datetime.assume_timezone(tz) {
+                time_tz::OffsetResult::Some(x) | time_tz::OffsetResult::Ambiguous(x, _) => x,
+                time_tz::OffsetResult::None => {
+                    datetime.assume_offset(datetime.assume_utc().to_timezone(tz).offset())
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod clock_test {
+    use super::{Clock, TimeZone};
+    use time::macros::datetime;
+
+    #[test]
+    fn test_named_zone() {
+        let zone = TimeZone::try_from("Europe/London".to_string()).unwrap();
+        let clock = Clock::new(Some(datetime!(2024-07-01 12:00 UTC)), Some(zone)).unwrap();
+
+        assert_eq!(clock.now(), datetime!(2024-07-01 13:00 +1));
+        assert_eq!(clock.now().offset(), time::macros::offset!(+1));
+        assert_eq!(
+            clock.local(datetime!(2024-01-01 12:00)),
+            datetime!(2024-01-01 12:00 UTC)
+        );
+        // Happens twice; the first one is still in summer time
+        assert_eq!(
+            clock.local(datetime!(2024-10-27 01:30)),
+            datetime!(2024-10-27 01:30 +1)
+        );
+        // Doesn't happen at all
+        let skipped = clock.local(datetime!(2024-03-31 01:30));
+        assert!(
+            datetime!(2024-03-31 00:30 UTC) <= skipped
+                && skipped <= datetime!(2024-03-31 02:30 UTC)
+        );
+
+        assert!(TimeZone::try_from("Europe/Nowhere".to_string()).is_err());
+    }
+}
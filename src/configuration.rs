@@ -1,17 +1,246 @@
+use crate::clock::{Clock, TimeZone};
 use secrecy::Secret;
+use std::collections::HashMap;
 use tracing::debug;
 
-#[derive(Clone, Debug, serde::Deserialize)]
+// Unlike everything below it, this doesn't deny unknown fields, because the AMCHECK_* environment
+// variables (including AMCHECK_CONFIG_FILE and AMCHECK_ENVIRONMENT) turn up here as keys.
+#[derive(Clone, Debug, serde::Deserialize, schemars::JsonSchema)]
 pub struct Settings {
+    // The single-account form; if imapserver is set, these (plus the top-level handlers) become
+    // an account named "default", in addition to anything in `accounts`.
+    pub imapserver: Option<String>,
+    pub login: Option<String>,
+    #[schemars(with = "Option<String>")]
+    pub password: Option<Secret<String>>,
+    #[serde(default)]
+    pub handlers: Vec<Handler>,
+    // These six have defaults in the config setup below; all but parallel_accounts are also the
+    // defaults for any account that doesn't set them itself
+    #[schemars(default = "default_inbox_name")]
+    pub inbox_name: String,
+    #[schemars(default = "default_storage_folder_name")]
+    pub storage_folder_name: String,
+    #[schemars(default = "default_days_back")]
+    pub days_back: i64,
+    #[schemars(default)]
+    pub gmail_delete_hack: bool,
+    // How many UIDs go in any one IMAP command; big folders are worked through in chunks of this
+    // many mails
+    #[schemars(default = "default_uid_chunk_size")]
+    pub uid_chunk_size: usize,
+    #[schemars(default)]
+    pub parallel_accounts: bool,
+    // Optional; the folders the move phase sweeps into storage.  Defaults to just inbox_name.
+    #[serde(default)]
+    pub source_folders: Vec<String>,
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+    // Named lists of handlers that accounts can pull in by name
+    #[serde(default)]
+    pub handler_sets: HashMap<String, Vec<Handler>>,
+    // Optional; where to keep each account's envelope cache between runs.  Without it, every
+    // check fetches every mail in storage.
+    pub envelope_cache_dir: Option<std::path::PathBuf>,
+    // Optional; the time zone that times of day (in DateCheck's `since` and ExpectSchedule's
+    // `cron`) are in.  Defaults to the system's.
+    pub timezone: Option<TimeZone>,
+}
+
+// One IMAP account, as written in the config file.
+#[derive(Clone, Debug, serde::Deserialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub name: String,
     pub imapserver: String,
     pub login: String,
+    #[schemars(with = "String")]
     pub password: Secret<String>,
+    #[serde(default)]
     pub handlers: Vec<Handler>,
-    // These four have defaults in the config setup below
-    pub inbox_name: String,
+    // Names of entries in the top-level `handler_sets`; their handlers run after this account's
+    // own `handlers`, in the order given
+    #[serde(default)]
+    pub handler_sets: Vec<String>,
+    // These fall back to the top-level values
+    pub inbox_name: Option<String>,
+    pub source_folders: Option<Vec<String>>,
+    pub storage_folder_name: Option<String>,
+    pub days_back: Option<i64>,
+    pub gmail_delete_hack: Option<bool>,
+    pub uid_chunk_size: Option<usize>,
+}
+
+// One IMAP account with all the defaults and handler sets filled in; this is what the move and
+// check phases actually run against.
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub name: String,
+    pub imapserver: String,
+    pub login: String,
+    pub password: Secret<String>,
+    pub handlers: Vec<Handler>,
+    // Never empty
+    pub source_folders: Vec<String>,
     pub storage_folder_name: String,
     pub days_back: i64,
     pub gmail_delete_hack: bool,
+    pub uid_chunk_size: usize,
+    pub envelope_cache_dir: Option<std::path::PathBuf>,
+}
+
+impl Account {
+    /// Every folder the move phase needs to look at: the account's own source folders, plus any
+    /// extra ones that handlers ask for, without duplicates.
+    pub fn all_source_folders(&self) -> Vec<&str> {
+        let mut folders: Vec<&str> = Vec::new();
+        let handler_folders = self.handlers.iter().flat_map(|x| &x.source_folders);
+        for folder in self.source_folders.iter().chain(handler_folders) {
+            if !folders.contains(&folder.as_str()) {
+                folders.push(folder);
+            }
+        }
+        folders
+    }
+
+    /// Every folder that mail gets stored in: the account's storage folder, plus any that handlers
+    /// have asked for, without duplicates.
+    pub fn all_storage_folders(&self) -> Vec<&str> {
+        let mut folders = vec![self.storage_folder_name.as_str()];
+        for handler in &self.handlers {
+            let folder = self.handler_storage_folder(handler);
+            if !folders.contains(&folder) {
+                folders.push(folder);
+            }
+        }
+        folders
+    }
+
+    /// Where the given handler's mail is stored.
+    pub fn handler_storage_folder<'a>(&'a self, handler: &'a Handler) -> &'a str {
+        handler
+            .storage_folder_name
+            .as_deref()
+            .unwrap_or(&self.storage_folder_name)
+    }
+
+    /// Whether the given handler applies to mail in the given source folder during the move
+    /// phase.
+    pub fn handler_watches(&self, handler: &Handler, folder: &str) -> bool {
+        if handler.source_folders.is_empty() {
+            self.source_folders.iter().any(|x| x == folder)
+        } else {
+            handler.source_folders.iter().any(|x| x == folder)
+        }
+    }
+}
+
+impl Settings {
+    /// Flattens the top-level single-account settings and the `accounts` list into the list of
+    /// accounts to run, resolving handler set names along the way.
+    pub fn accounts(&self) -> Result<Vec<Account>, config::ConfigError> {
+        let mut accounts = Vec::new();
+
+        match (&self.imapserver, &self.login, &self.password) {
+            (Some(imapserver), Some(login), Some(password)) => accounts.push(Account {
+                name: "default".to_string(),
+                imapserver: imapserver.clone(),
+                login: login.clone(),
+                password: password.clone(),
+                handlers: self.handlers.clone(),
+                source_folders: self.default_source_folders(),
+                storage_folder_name: self.storage_folder_name.clone(),
+                days_back: self.days_back,
+                gmail_delete_hack: self.gmail_delete_hack,
+                uid_chunk_size: self.uid_chunk_size,
+                envelope_cache_dir: self.envelope_cache_dir.clone(),
+            }),
+            (None, None, None) => {
+                if !self.handlers.is_empty() {
+                    return Err(config::ConfigError::Message(
+                        "Top-level handlers are only used with the top-level imapserver; use handler_sets to share handlers between accounts".to_string(),
+                    ));
+                }
+            }
+            _ => {
+                return Err(config::ConfigError::Message(
+                    "imapserver, login and password must be given together".to_string(),
+                ))
+            }
+        }
+
+        for account in &self.accounts {
+            let mut handlers = account.handlers.clone();
+            for set_name in &account.handler_sets {
+                let Some(set) = self.handler_sets.get(set_name) else {
+                    return Err(config::ConfigError::Message(format!(
+                        "Account '{}' refers to unknown handler set '{set_name}'",
+                        account.name
+                    )));
+                };
+                handlers.extend(set.iter().cloned());
+            }
+
+            accounts.push(Account {
+                name: account.name.clone(),
+                imapserver: account.imapserver.clone(),
+                login: account.login.clone(),
+                password: account.password.clone(),
+                handlers,
+                source_folders: match (&account.source_folders, &account.inbox_name) {
+                    (Some(folders), _) if !folders.is_empty() => folders.clone(),
+                    (_, Some(inbox_name)) => vec![inbox_name.clone()],
+                    _ => self.default_source_folders(),
+                },
+                storage_folder_name: account
+                    .storage_folder_name
+                    .clone()
+                    .unwrap_or_else(|| self.storage_folder_name.clone()),
+                days_back: account.days_back.unwrap_or(self.days_back),
+                gmail_delete_hack: account.gmail_delete_hack.unwrap_or(self.gmail_delete_hack),
+                uid_chunk_size: account.uid_chunk_size.unwrap_or(self.uid_chunk_size),
+                envelope_cache_dir: self.envelope_cache_dir.clone(),
+            });
+        }
+
+        if accounts.is_empty() {
+            return Err(config::ConfigError::Message(
+                "No accounts configured; set imapserver, login and password, or add some accounts"
+                    .to_string(),
+            ));
+        }
+
+        let mut names = std::collections::HashSet::new();
+        for account in &accounts {
+            if !names.insert(&account.name) {
+                return Err(config::ConfigError::Message(format!(
+                    "Account name '{}' is used more than once",
+                    account.name
+                )));
+            }
+
+            // Moving a folder into itself is at best pointless
+            let source_folders = account.all_source_folders();
+            for storage_folder in account.all_storage_folders() {
+                if source_folders.contains(&storage_folder) {
+                    return Err(config::ConfigError::Message(format!(
+                        "Account '{}' uses the storage folder '{storage_folder}' as a source folder",
+                        account.name
+                    )));
+                }
+            }
+        }
+
+        Ok(accounts)
+    }
+
+    fn default_source_folders(&self) -> Vec<String> {
+        if self.source_folders.is_empty() {
+            vec![self.inbox_name.clone()]
+        } else {
+            self.source_folders.clone()
+        }
+    }
 }
 
 // FIXME: put this in the readme
@@ -24,38 +253,62 @@ pub struct Settings {
 // Examples of the sorts of things we do with the check tree: "Must be at least one puppet run in
 // the past day" and "alert on failed puppet runs" and "delete successful puppet runs older than 2
 // days".
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Handler {
     pub name: String,
     pub filters: Vec<Filter>,
     pub checker_tree: CheckerTree,
+    // Optional; limits the move phase for this handler to just these folders, instead of the
+    // account's source folders
+    #[serde(default)]
+    pub source_folders: Vec<String>,
+    // Optional; stores this handler's mail in its own folder instead of the account's storage
+    // folder, so the check phase only has to look at that folder for this handler
+    pub storage_folder_name: Option<String>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub enum CheckerTree {
     Stop,
     Action(Action),
     MatchCheck(MatchCheck),
     DateCheck(DateCheck),
     CountCheck(CountCheck),
+    CountRangeCheck(CountRangeCheck),
+    RatioCheck(RatioCheck),
+    ExtractCheck(ExtractCheck),
+    AggregateCheck(AggregateCheck),
+    GroupBy(GroupBy),
+    Correlate(Correlate),
+    Latest(Latest),
+    DuplicateCheck(DuplicateCheck),
+    SizeCheck(SizeCheck),
+    AttachmentCheck(AttachmentCheck),
     BodyCheckAny(BodyCheckAny),
     BodyCheckAll(BodyCheckAll),
     BodyCheckRegex(BodyCheckRegex),
+    ExpectSchedule(ExpectSchedule),
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
 pub enum MatchEmpty {
     Matched,
     NotMatched,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
 pub enum DateEmpty {
     YoungerThan,
     OlderThan,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct MatchCheck {
     pub matchers: Vec<Filter>,
     pub empty_ok: MatchEmpty,
@@ -63,48 +316,836 @@ pub struct MatchCheck {
     pub not_matched: Box<CheckerTree>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(try_from = "DateCheckFields", into = "DateCheckFields")]
 pub struct DateCheck {
-    pub days: u8,
+    pub cutoff: Cutoff,
     pub empty_ok: DateEmpty,
     pub older_than: Box<CheckerTree>,
     pub younger_than: Box<CheckerTree>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+// DateCheck as it's written in the config file, where the cutoff can be given in one of several
+// fields (but only one).
+#[derive(Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct DateCheckFields {
+    // A number of whole days ago
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days: Option<u8>,
+    // A length of time ago, like "6h"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age: Option<Interval>,
+    // A time of day, like "today 06:00"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<Since>,
+    empty_ok: DateEmpty,
+    older_than: Box<CheckerTree>,
+    younger_than: Box<CheckerTree>,
+}
+
+impl TryFrom<DateCheckFields> for DateCheck {
+    type Error = String;
+
+    fn try_from(fields: DateCheckFields) -> Result<Self, Self::Error> {
+        let cutoff = match (fields.days, fields.age, fields.since) {
+            (Some(days), None, None) => Cutoff::Days(days),
+            (None, Some(age), None) => Cutoff::Age(age),
+            (None, None, Some(since)) => Cutoff::Since(since),
+            _ => return Err("DateCheck needs exactly one of `days`, `age` or `since`".to_string()),
+        };
+
+        Ok(DateCheck {
+            cutoff,
+            empty_ok: fields.empty_ok,
+            older_than: fields.older_than,
+            younger_than: fields.younger_than,
+        })
+    }
+}
+
+impl From<DateCheck> for DateCheckFields {
+    fn from(check: DateCheck) -> Self {
+        DateCheckFields {
+            days: match check.cutoff {
+                Cutoff::Days(days) => Some(days),
+                _ => None,
+            },
+            age: match check.cutoff {
+                Cutoff::Age(age) => Some(age),
+                _ => None,
+            },
+            since: match check.cutoff {
+                Cutoff::Since(since) => Some(since),
+                _ => None,
+            },
+            empty_ok: check.empty_ok,
+            older_than: check.older_than,
+            younger_than: check.younger_than,
+        }
+    }
+}
+
+/// Where a DateCheck splits older mail from younger mail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cutoff {
+    Days(u8),
+    Age(Interval),
+    Since(Since),
+}
+
+impl Cutoff {
+    /// The actual cutoff time, given the current time; None if that's out of range.
+    pub fn before(&self, clock: &Clock) -> Option<time::OffsetDateTime> {
+        match self {
+            Cutoff::Days(days) => clock
+                .now()
+                .checked_sub(time::Duration::days(i64::from(*days))),
+            Cutoff::Age(age) => clock.now().checked_sub(age.0),
+            Cutoff::Since(since) => since.before(clock),
+        }
+    }
+}
+
+impl std::fmt::Display for Cutoff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Cutoff::Days(days) => write!(f, "{days}"),
+            Cutoff::Age(age) => write!(f, "{age}"),
+            Cutoff::Since(since) => write!(f, "since {since}"),
+        }
+    }
+}
+
+/// A length of time, written as a number and a unit (`s`, `m`, `h`, `d` or `w`), or several of
+/// those together: "90m", "6h", "2d", "1h30m".
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct Interval(pub time::Duration);
+
+impl std::str::FromStr for Interval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{s}' isn't a length of time like \"90m\", \"6h\" or \"2d\"");
+
+        let mut total = time::Duration::ZERO;
+        let mut rest = s.trim();
+        if rest.is_empty() {
+            return Err(invalid());
+        }
+
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|x: char| !x.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let (number, after) = rest.split_at(digits);
+            let number: i32 = number.parse().map_err(|_| invalid())?;
+
+            let mut chars = after.chars();
+            let unit = match chars.next() {
+                Some('s') => time::Duration::SECOND,
+                Some('m') => time::Duration::MINUTE,
+                Some('h') => time::Duration::HOUR,
+                Some('d') => time::Duration::DAY,
+                Some('w') => time::Duration::WEEK,
+                _ => return Err(invalid()),
+            };
+            rest = chars.as_str();
+
+            total = unit
+                .checked_mul(number)
+                .and_then(|x| total.checked_add(x))
+                .ok_or_else(invalid)?;
+        }
+
+        Ok(Interval(total))
+    }
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut seconds = self.0.whole_seconds();
+        if seconds == 0 {
+            return write!(f, "0s");
+        }
+
+        for (unit, length) in [
+            ("w", 604_800),
+            ("d", 86_400),
+            ("h", 3_600),
+            ("m", 60),
+            ("s", 1),
+        ] {
+            if seconds >= length {
+                write!(f, "{}{unit}", seconds / length)?;
+                seconds %= length;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for Interval {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Interval> for String {
+    fn from(interval: Interval) -> Self {
+        interval.to_string()
+    }
+}
+
+/// A time of day: "today 06:00", "yesterday 18:30", or just "06:00" for whichever of today's and
+/// yesterday's was most recent.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct Since {
+    pub day: SinceDay,
+    pub time: time::Time,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SinceDay {
+    Today,
+    Yesterday,
+    MostRecent,
+}
+
+impl Since {
+    /// The point in time this refers to, given the current time; None if that's out of range.
+    pub fn before(&self, clock: &Clock) -> Option<time::OffsetDateTime> {
+        let today = clock.now().date();
+        let on = |date: time::Date| clock.local(date.with_time(self.time));
+        match self.day {
+            SinceDay::Today => Some(on(today)),
+            SinceDay::Yesterday => today.previous_day().map(on),
+            SinceDay::MostRecent if on(today) <= clock.now() => Some(on(today)),
+            SinceDay::MostRecent => today.previous_day().map(on),
+        }
+    }
+}
+
+impl std::str::FromStr for Since {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "'{s}' isn't a time of day like \"today 06:00\", \"yesterday 18:30\" or \"06:00\""
+            )
+        };
+
+        let (day, time) = match s.trim().split_once(' ') {
+            Some(("today", time)) => (SinceDay::Today, time),
+            Some(("yesterday", time)) => (SinceDay::Yesterday, time),
+            Some(_) => return Err(invalid()),
+            None => (SinceDay::MostRecent, s.trim()),
+        };
+
+        let mut parts = time.trim().split(':').map(str::parse::<u8>);
+        let (Some(Ok(hour)), Some(Ok(minute)), None) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let time = time::Time::from_hms(hour, minute, 0).map_err(|_| invalid())?;
+
+        Ok(Since { day, time })
+    }
+}
+
+impl std::fmt::Display for Since {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.day {
+            SinceDay::Today => write!(f, "today ")?,
+            SinceDay::Yesterday => write!(f, "yesterday ")?,
+            SinceDay::MostRecent => {}
+        }
+        write!(f, "{:02}:{:02}", self.time.hour(), self.time.minute())
+    }
+}
+
+impl TryFrom<String> for Since {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Since> for String {
+    fn from(since: Since) -> Self {
+        since.to_string()
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct CountCheck {
-    pub count: u8,
+    pub count: u32,
     pub greater_than: Box<CheckerTree>,
     pub less_than: Box<CheckerTree>,
     pub equal: Box<CheckerTree>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+// Like CountCheck, but against a range: fewer than `min` mails go to `below`, more than `max` go
+// to `above`, and anything in between (inclusive) goes to `in_range`.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CountRangeCheck {
+    #[serde(default)]
+    pub min: u32,
+    // Optional; no maximum if it's not set
+    pub max: Option<u32>,
+    pub below: Box<CheckerTree>,
+    pub in_range: Box<CheckerTree>,
+    pub above: Box<CheckerTree>,
+}
+
+// Works out what percentage the mails picked out by `numerator` are of those picked out by
+// `denominator` (by default, all the mails that reach it), and sends all the mails down `below`,
+// `in_range` or `above` depending on how that compares to `min_percent` and `max_percent`.  No
+// mails at all counts as 0%.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RatioCheck {
+    pub numerator: Selection,
+    pub denominator: Option<Selection>,
+    #[serde(default)]
+    pub min_percent: f64,
+    // Optional; no maximum if it's not set
+    pub max_percent: Option<f64>,
+    pub below: Box<CheckerTree>,
+    pub in_range: Box<CheckerTree>,
+    pub above: Box<CheckerTree>,
+}
+
+// A way of picking out some of a set of mails; the same tests as MatchCheck and the BodyChecks.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+pub enum Selection {
+    Matchers(Vec<Filter>),
+    BodyAny(Vec<String>),
+    BodyAll(Vec<String>),
+    #[serde(with = "serde_regex")]
+    #[schemars(with = "String")]
+    BodyRegex(regex::Regex),
+}
+
+// Pulls a number out of each mail with `capture`, and splits the mails by whether it passes
+// `gt`, `lt` or `between` (exactly one of them).  Mails where the capture doesn't match, or
+// doesn't capture a number, go to `not_found`.  The values are noted for any Alert further down.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(try_from = "ExtractCheckFields", into = "ExtractCheckFields")]
+pub struct ExtractCheck {
+    pub capture: Capture,
+    pub comparison: Comparison,
+    pub empty_ok: MatchEmpty,
+    pub matched: Box<CheckerTree>,
+    pub not_matched: Box<CheckerTree>,
+    pub not_found: Box<CheckerTree>,
+}
+
+// ExtractCheck as it's written in the config file, with the comparison in one of several fields.
+#[derive(Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct ExtractCheckFields {
+    capture: Capture,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lt: Option<f64>,
+    // Inclusive at both ends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    between: Option<(f64, f64)>,
+    empty_ok: MatchEmpty,
+    matched: Box<CheckerTree>,
+    not_matched: Box<CheckerTree>,
+    not_found: Box<CheckerTree>,
+}
+
+impl TryFrom<ExtractCheckFields> for ExtractCheck {
+    type Error = String;
+
+    fn try_from(fields: ExtractCheckFields) -> Result<Self, Self::Error> {
+        let comparison =
+            Comparison::from_fields("ExtractCheck", fields.gt, fields.lt, fields.between)?;
+
+        Ok(ExtractCheck {
+            capture: fields.capture,
+            comparison,
+            empty_ok: fields.empty_ok,
+            matched: fields.matched,
+            not_matched: fields.not_matched,
+            not_found: fields.not_found,
+        })
+    }
+}
+
+impl From<ExtractCheck> for ExtractCheckFields {
+    fn from(check: ExtractCheck) -> Self {
+        let (gt, lt, between) = check.comparison.to_fields();
+
+        ExtractCheckFields {
+            capture: check.capture,
+            gt,
+            lt,
+            between,
+            empty_ok: check.empty_ok,
+            matched: check.matched,
+            not_matched: check.not_matched,
+            not_found: check.not_found,
+        }
+    }
+}
+
+// Pulls a number out of each mail with `capture`, like ExtractCheck, and combines them with
+// `aggregate`; all the mails go down `matched` or `not_matched` depending on whether the result
+// passes `gt`, `lt` or `between` (exactly one of them), or down `not_found` if none of them had a
+// number (including when there are no mails at all).
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(try_from = "AggregateCheckFields", into = "AggregateCheckFields")]
+pub struct AggregateCheck {
+    pub capture: Capture,
+    pub aggregate: Aggregate,
+    pub comparison: Comparison,
+    pub matched: Box<CheckerTree>,
+    pub not_matched: Box<CheckerTree>,
+    pub not_found: Box<CheckerTree>,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AggregateCheckFields {
+    capture: Capture,
+    aggregate: Aggregate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lt: Option<f64>,
+    // Inclusive at both ends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    between: Option<(f64, f64)>,
+    matched: Box<CheckerTree>,
+    not_matched: Box<CheckerTree>,
+    not_found: Box<CheckerTree>,
+}
+
+impl TryFrom<AggregateCheckFields> for AggregateCheck {
+    type Error = String;
+
+    fn try_from(fields: AggregateCheckFields) -> Result<Self, Self::Error> {
+        Ok(AggregateCheck {
+            comparison: Comparison::from_fields(
+                "AggregateCheck",
+                fields.gt,
+                fields.lt,
+                fields.between,
+            )?,
+            capture: fields.capture,
+            aggregate: fields.aggregate,
+            matched: fields.matched,
+            not_matched: fields.not_matched,
+            not_found: fields.not_found,
+        })
+    }
+}
+
+impl From<AggregateCheck> for AggregateCheckFields {
+    fn from(check: AggregateCheck) -> Self {
+        let (gt, lt, between) = check.comparison.to_fields();
+
+        AggregateCheckFields {
+            capture: check.capture,
+            aggregate: check.aggregate,
+            gt,
+            lt,
+            between,
+            matched: check.matched,
+            not_matched: check.not_matched,
+            not_found: check.not_found,
+        }
+    }
+}
+
+/// How an AggregateCheck combines the numbers it finds.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+pub enum Aggregate {
+    Sum,
+    Average,
+    Min,
+    Max,
+    // The one from the mail with the most recent date
+    Latest,
+}
+
+impl Aggregate {
+    /// The result for the given numbers, each with the date of the mail it came from; None if
+    /// there aren't any.
+    pub fn apply(self, values: &[(time::OffsetDateTime, f64)]) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+
+        let numbers = values.iter().map(|x| x.1);
+        Some(match self {
+            Aggregate::Sum => numbers.sum(),
+            #[allow(clippy::cast_precision_loss)]
+            Aggregate::Average => numbers.sum::<f64>() / values.len() as f64,
+            Aggregate::Min => numbers.fold(f64::INFINITY, f64::min),
+            Aggregate::Max => numbers.fold(f64::NEG_INFINITY, f64::max),
+            Aggregate::Latest => values.iter().max_by_key(|x| x.0)?.1,
+        })
+    }
+}
+
+// Splits the mails up by what `key` captures from them (a host name, say), and runs `subtree`
+// once for each group, so that a CountCheck in it counts each host separately.  Each of
+// `expected_keys` gets a run even if no mail has that key, with no mails.  Mails that `key`
+// doesn't find anything in go down `no_key`, if it's set.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GroupBy {
+    pub key: Capture,
+    pub subtree: Box<CheckerTree>,
+    #[serde(default)]
+    pub expected_keys: Vec<String>,
+    pub no_key: Option<Box<CheckerTree>>,
+}
+
+// For automations that send a mail when they start and another when they finish: pairs each
+// `start` mail with the first `finish` mail after it with the same `key` (a job ID, say).  The
+// pairs go down `paired` (which runs even with none, so a CountCheck under it can notice), and
+// starts that have gone `timeout` without a finish go down `timed_out`.  Starts still within
+// the timeout go down `pending`, and everything else (finishes without a start, mails that are
+// neither, mails without a key) goes down `other`, if those are set.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Correlate {
+    pub start: Selection,
+    pub finish: Selection,
+    pub key: Capture,
+    pub timeout: Interval,
+    pub paired: Box<CheckerTree>,
+    pub timed_out: Box<CheckerTree>,
+    pub pending: Option<Box<CheckerTree>>,
+    pub other: Option<Box<CheckerTree>>,
+}
+
+// For status reports, where only the newest matters: sends the `n` most recent mails down
+// `latest` (which runs even if there aren't any), and the rest down `rest` (typically to be
+// deleted).
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Latest {
+    #[serde(default = "default_latest_n")]
+    #[schemars(default = "default_latest_n")]
+    pub n: u32,
+    pub latest: Box<CheckerTree>,
+    pub rest: Box<CheckerTree>,
+}
+
+fn default_latest_n() -> u32 {
+    1
+}
+
+// For spotting the same mail arriving more than once: the earliest mail with each key goes down
+// `originals` (which runs even if there aren't any), and later ones with the same key down
+// `duplicates`.  With `window`, a mail only counts as a duplicate if it's within that long of its
+// original; otherwise it starts over as an original itself.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DuplicateCheck {
+    pub by: DuplicateKey,
+    pub window: Option<Interval>,
+    pub originals: Box<CheckerTree>,
+    pub duplicates: Box<CheckerTree>,
+}
+
+// Sorts mails by their size in bytes: smaller than `min_bytes` go to `below`, bigger than
+// `max_bytes` go to `above`, and anything in between (inclusive) goes to `in_range`, which runs
+// even if there aren't any, so a CountCheck under it can notice.  Mails the server didn't give a
+// size for go to `unknown`, if it's set.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SizeCheck {
+    #[serde(default)]
+    pub min_bytes: u32,
+    // Optional; no maximum if it's not set
+    pub max_bytes: Option<u32>,
+    pub below: Box<CheckerTree>,
+    pub in_range: Box<CheckerTree>,
+    pub above: Box<CheckerTree>,
+    pub unknown: Option<Box<CheckerTree>>,
+}
+
+// Sends mails with an attachment down `matched`, and the rest down `not_matched`.  With
+// `filename` and/or `mime_type`, only attachments whose filename and MIME type (e.g.
+// "text/plain") match those regexes count.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AttachmentCheck {
+    #[serde(default, with = "serde_regex")]
+    #[schemars(with = "Option<String>")]
+    pub filename: Option<regex::Regex>,
+    #[serde(default, with = "serde_regex")]
+    #[schemars(with = "Option<String>")]
+    pub mime_type: Option<regex::Regex>,
+    pub empty_ok: MatchEmpty,
+    pub matched: Box<CheckerTree>,
+    pub not_matched: Box<CheckerTree>,
+}
+
+/// What makes two mails the same, for a DuplicateCheck.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+pub enum DuplicateKey {
+    // Mails without a Message-ID are never duplicates
+    MessageId,
+    // Compared ignoring case and runs of whitespace
+    SubjectFrom,
+}
+
+impl DuplicateKey {
+    /// The key for a mail with these headers, or None if it doesn't have one.
+    pub fn key_for(self, message_id: Option<&str>, subject: &str, from: &str) -> Option<String> {
+        let normalize = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+        match self {
+            DuplicateKey::MessageId => message_id
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(str::to_string),
+            DuplicateKey::SubjectFrom => Some(format!(
+                "{}\n{}",
+                normalize(&subject.to_lowercase()),
+                normalize(&from.to_lowercase())
+            )),
+        }
+    }
+}
+
+/// What a number has to be for an ExtractCheck to count it as matched.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Gt(f64),
+    Lt(f64),
+    Between(f64, f64),
+}
+
+// Checks with a comparison have it written in the config file as exactly one of `gt`, `lt` or
+// `between`.
+type ComparisonFields = (Option<f64>, Option<f64>, Option<(f64, f64)>);
+
+impl Comparison {
+    fn from_fields(
+        node: &str,
+        gt: Option<f64>,
+        lt: Option<f64>,
+        between: Option<(f64, f64)>,
+    ) -> Result<Comparison, String> {
+        match (gt, lt, between) {
+            (Some(x), None, None) => Ok(Comparison::Gt(x)),
+            (None, Some(x), None) => Ok(Comparison::Lt(x)),
+            (None, None, Some((low, high))) => Ok(Comparison::Between(low, high)),
+            _ => Err(format!(
+                "{node} needs exactly one of `gt`, `lt` or `between`"
+            )),
+        }
+    }
+
+    fn to_fields(self) -> ComparisonFields {
+        match self {
+            Comparison::Gt(x) => (Some(x), None, None),
+            Comparison::Lt(x) => (None, Some(x), None),
+            Comparison::Between(low, high) => (None, None, Some((low, high))),
+        }
+    }
+
+    pub fn matches(&self, value: f64) -> bool {
+        match self {
+            Comparison::Gt(x) => value > *x,
+            Comparison::Lt(x) => value < *x,
+            Comparison::Between(low, high) => *low <= value && value <= *high,
+        }
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Comparison::Gt(x) => write!(f, "> {x}"),
+            Comparison::Lt(x) => write!(f, "< {x}"),
+            Comparison::Between(low, high) => write!(f, "between {low} and {high}"),
+        }
+    }
+}
+
+/// A regex with a named capture group, applied to one part of a mail; what it captures is the
+/// first named group's text.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(try_from = "CaptureFields", into = "CaptureFields")]
+pub struct Capture {
+    pub field: CaptureField,
+    pub regex: regex::Regex,
+    pub name: String,
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct CaptureFields {
+    field: CaptureField,
+    #[serde(with = "serde_regex")]
+    #[schemars(with = "String")]
+    regex: regex::Regex,
+}
+
+impl TryFrom<CaptureFields> for Capture {
+    type Error = String;
+
+    fn try_from(fields: CaptureFields) -> Result<Self, Self::Error> {
+        let name = fields
+            .regex
+            .capture_names()
+            .flatten()
+            .next()
+            .ok_or_else(|| {
+                format!(
+                    "The regex '{}' needs a named capture group, like (?<value>[0-9]+)",
+                    fields.regex
+                )
+            })?
+            .to_string();
+
+        Ok(Capture {
+            field: fields.field,
+            regex: fields.regex,
+            name,
+        })
+    }
+}
+
+impl From<Capture> for CaptureFields {
+    fn from(capture: Capture) -> Self {
+        CaptureFields {
+            field: capture.field,
+            regex: capture.regex,
+        }
+    }
+}
+
+impl Capture {
+    /// What the capture group picks out of the given text, if anything.
+    pub fn find<'t>(&self, text: &'t str) -> Option<&'t str> {
+        self.regex
+            .captures(text)
+            .and_then(|x| x.name(&self.name))
+            .map(|x| x.as_str())
+    }
+}
+
+impl std::fmt::Display for Capture {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} from {:?} /{}/", self.name, self.field, self.regex)
+    }
+}
+
+/// The part of a mail a Capture looks at; the body is decoded (from quoted-printable, base64 and
+/// so on) first, and for multipart mail it's the first text/plain part.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+pub enum CaptureField {
+    Subject,
+    From,
+    Body,
+    Header(String),
+}
+
+// For mail from something that runs on a schedule: works out when it should have run in the last
+// `lookback`, and expects a mail from each run within `grace` of it.  The mails that came from a
+// run go to `ok`, which also gets run (possibly with no mails) if no runs were missed; `missing`
+// gets run with no mails if any runs were missed; and mails that don't match up with any run go
+// to `unexpected_extra`.  Mails from before the lookback window go to `ok`, since there's nothing
+// to say about them.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectSchedule {
+    pub cron: crate::cron::Schedule,
+    pub grace: Interval,
+    pub lookback: Interval,
+    pub missing: Box<CheckerTree>,
+    pub ok: Box<CheckerTree>,
+    pub unexpected_extra: Box<CheckerTree>,
+}
+
+/// How BodyCheckAny and BodyCheckAll look for their strings.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Deserialize,
+    serde::Serialize,
+    schemars::JsonSchema,
+)]
+pub enum BodySearch {
+    // An IMAP SEARCH on the server; quick, but whether it matches parts of words, or cares about
+    // case, is up to the server
+    #[default]
+    Server,
+    // Fetches the bodies and looks for the strings anywhere in their decoded text, exactly as
+    // given
+    Substring,
+    // Likewise, but ignoring case
+    SubstringIgnoreCase,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct BodyCheckAny {
     pub strings: Vec<String>,
+    #[serde(default)]
+    pub search: BodySearch,
     pub empty_ok: MatchEmpty,
     pub matched: Box<CheckerTree>,
     pub not_matched: Box<CheckerTree>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct BodyCheckAll {
     pub strings: Vec<String>,
+    #[serde(default)]
+    pub search: BodySearch,
     pub empty_ok: MatchEmpty,
     pub matched: Box<CheckerTree>,
     pub not_matched: Box<CheckerTree>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct BodyCheckRegex {
     #[serde(with = "serde_regex")]
+    #[schemars(with = "String")]
     pub regex: regex::Regex,
     pub empty_ok: MatchEmpty,
     pub matched: Box<CheckerTree>,
     pub not_matched: Box<CheckerTree>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub enum Action {
     Alert,
     Delete,
@@ -112,38 +1153,171 @@ pub enum Action {
     Nothing,
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub enum Filter {
     Match(MatcherPart),
     UnMatch(MatcherPart),
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, schemars::JsonSchema)]
 pub enum MatcherPart {
     #[serde(with = "serde_regex")]
+    #[schemars(with = "String")]
     Subject(regex::Regex),
     #[serde(with = "serde_regex")]
+    #[schemars(with = "String")]
     From(regex::Regex),
 }
 
+#[cfg(test)]
+mod cutoff_test {
+    use crate::clock::Clock;
+    use crate::configuration::{Interval, Since};
+    use time::macros::datetime;
+
+    #[test]
+    fn test_interval() {
+        let interval: Interval = "1h30m".parse().unwrap();
+        assert_eq!(interval.0, time::Duration::minutes(90));
+        assert_eq!(interval.to_string(), "1h30m");
+        assert_eq!("2d".parse::<Interval>().unwrap().0, time::Duration::days(2));
+
+        for bad in ["", "90", "m", "6 h", "2y", "-1h"] {
+            assert!(bad.parse::<Interval>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_since() {
+        let now = &Clock::fixed(datetime!(2024-03-10 05:00 -8));
+
+        let today: Since = "today 06:00".parse().unwrap();
+        assert_eq!(today.before(now), Some(datetime!(2024-03-10 06:00 -8)));
+
+        let yesterday: Since = "yesterday 18:30".parse().unwrap();
+        assert_eq!(yesterday.before(now), Some(datetime!(2024-03-09 18:30 -8)));
+        assert_eq!(yesterday.to_string(), "yesterday 18:30");
+
+        // Hasn't happened yet today, so it's yesterday's
+        let recent: Since = "06:00".parse().unwrap();
+        assert_eq!(recent.before(now), Some(datetime!(2024-03-09 06:00 -8)));
+        let recent: Since = "4:15".parse().unwrap();
+        assert_eq!(recent.before(now), Some(datetime!(2024-03-10 04:15 -8)));
+
+        for bad in ["", "tomorrow 06:00", "25:00", "06", "06:00:00"] {
+            assert!(bad.parse::<Since>().is_err(), "{bad}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod extract_test {
+    use crate::configuration::{Aggregate, CaptureField, Comparison, ExtractCheck};
+    use time::macros::datetime;
+
+    #[test]
+    fn test_extract_check() {
+        let check: ExtractCheck = json5::from_str(
+            r#"{
+              capture: { field: "Body", regex: "Disk usage: (?<disk>[0-9.]+)%" },
+              gt: 90,
+              empty_ok: "NotMatched",
+              matched: { Action: "Alert" },
+              not_matched: { Action: "Success" },
+              not_found: { Action: "Alert" },
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(check.capture.field, CaptureField::Body);
+        assert_eq!(check.capture.name, "disk");
+        assert_eq!(check.capture.find("Disk usage: 93.5% of /"), Some("93.5"));
+        assert_eq!(check.capture.find("Disk usage: unknown"), None);
+        assert_eq!(check.comparison, Comparison::Gt(90.0));
+        assert!(check.comparison.matches(93.5));
+        assert!(Comparison::Between(1.0, 2.0).matches(2.0));
+
+        let values = [
+            (datetime!(2024-03-10 05:00 UTC), 4.0),
+            (datetime!(2024-03-12 05:00 UTC), 1.0),
+            (datetime!(2024-03-11 05:00 UTC), 7.0),
+        ];
+        assert_eq!(Aggregate::Sum.apply(&values), Some(12.0));
+        assert_eq!(Aggregate::Average.apply(&values), Some(4.0));
+        assert_eq!(Aggregate::Min.apply(&values), Some(1.0));
+        assert_eq!(Aggregate::Max.apply(&values), Some(7.0));
+        assert_eq!(Aggregate::Latest.apply(&values), Some(1.0));
+        assert_eq!(Aggregate::Sum.apply(&[]), None);
+
+        // Unnamed group, and two comparisons
+        for (regex, comparison) in [("([0-9]+)", "lt: 1"), ("(?<n>[0-9]+)", "lt: 1, gt: 2")] {
+            let bad = format!(
+                r#"{{
+                  capture: {{ field: "Subject", regex: "{regex}" }},
+                  {comparison},
+                  empty_ok: "Matched",
+                  matched: "Stop",
+                  not_matched: "Stop",
+                  not_found: "Stop",
+                }}"#
+            );
+            let error = json5::from_str::<ExtractCheck>(&bad)
+                .unwrap_err()
+                .to_string();
+            assert!(
+                error.contains("named capture group") || error.contains("exactly one of"),
+                "{error}"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod duplicate_test {
+    use crate::configuration::DuplicateKey;
+
+    #[test]
+    fn test_key_for() {
+        let key = |id, subject, from| DuplicateKey::SubjectFrom.key_for(id, subject, from);
+        assert_eq!(
+            key(None, "Cron <root@web1>  backup", "Cron Daemon <root@web1>"),
+            key(
+                Some("<a@b>"),
+                " cron <ROOT@web1> backup",
+                "cron daemon  <root@WEB1>"
+            )
+        );
+        assert_ne!(
+            key(None, "Cron <root@web1> backup", "root@web1"),
+            key(None, "Cron <root@web2> backup", "root@web1")
+        );
+
+        let id = |id| DuplicateKey::MessageId.key_for(id, "Subject", "from@example.com");
+        assert_eq!(
+            id(Some(" <1@example.com> ")),
+            Some("<1@example.com>".into())
+        );
+        assert_eq!(id(Some("")), None);
+        assert_eq!(id(None), None);
+    }
+}
+
 // This isn't *really* a test, it's an exploration tool for
 // testing JSON serialization; make the TestConfig struct you
 // want and output it here, so you can see what it looks like
 // in JSON.
 //
-// Note that this is currently the only place the `serde_json` crate is used
-//
 // Must be run with `cargo test -- --nocapture` to be of any use
 #[cfg(test)]
 mod json_test {
     use crate::configuration::Action::*;
-    use crate::configuration::BodyCheckAll;
     use crate::configuration::CheckerTree::*;
     use crate::configuration::DateEmpty::*;
     use crate::configuration::Filter::*;
     use crate::configuration::MatchEmpty;
     use crate::configuration::MatcherPart::*;
-    use crate::configuration::{CountCheck, DateCheck, Handler, MatchCheck};
+    use crate::configuration::{BodyCheckAll, BodySearch};
+    use crate::configuration::{CountCheck, Cutoff, DateCheck, Handler, MatchCheck};
 
     #[test]
     fn test_json_output() {
@@ -164,9 +1338,10 @@ mod json_test {
                     matched: Box::new(BodyCheckAll(BodyCheckAll {
                         empty_ok: MatchEmpty::Matched,
                         strings: vec!["Notice: Applied catalog in".to_string()],
+                        search: BodySearch::Server,
                         matched: Box::new(DateCheck(DateCheck {
                             empty_ok: YoungerThan,
-                            days: 1,
+                            cutoff: Cutoff::Days(1),
                             older_than: Box::new(Action(Delete)),
                             younger_than: Box::new(CountCheck(CountCheck {
                                 count: 1,
@@ -179,6 +1354,8 @@ mod json_test {
                     })),
                     not_matched: Box::new(Stop),
                 }),
+                source_folders: vec![],
+                storage_folder_name: None,
             },
         };
         println!("json test original:\n{:#?}", test);
@@ -253,36 +1430,56 @@ mod json_test {
     }
 }
 
-pub fn get_environment() -> Environment {
-    // Detect the running environment.
-    // Default to `prod` if unspecified.
-    let environment: Environment = std::env::var("AMCHECK_ENVIRONMENT")
-        .unwrap_or_else(|_| "prod".into())
-        .try_into()
-        .expect("Failed to parse AMCHECK_ENVIRONMENT.");
+fn default_inbox_name() -> String {
+    "INBOX".to_string()
+}
 
-    environment
+fn default_storage_folder_name() -> String {
+    "amcheck_storage".to_string()
 }
 
-pub fn get_configuration() -> Result<Settings, config::ConfigError> {
-    let base_path = std::env::current_dir().expect("Failed to determine the current directory");
-    let configuration_directory = base_path.join("settings");
-    let environment = get_environment();
-    let environment_filename = format!("{}.json5", environment.as_str());
+fn default_days_back() -> i64 {
+    60
+}
 
-    let config_file: std::path::PathBuf = match std::env::var("AMCHECK_CONFIG_FILE") {
-        Ok(name) => name.into(),
-        Err(_) => configuration_directory.join(environment_filename),
-    };
+fn default_uid_chunk_size() -> usize {
+    1000
+}
+
+/// The config file to read: the one given, or `settings/<environment>.json5` in the current
+/// directory.
+pub fn config_file_path(
+    config_file: Option<&std::path::Path>,
+    environment: &Environment,
+) -> std::path::PathBuf {
+    match config_file {
+        Some(name) => name.into(),
+        None => {
+            let base_path =
+                std::env::current_dir().expect("Failed to determine the current directory");
+            let configuration_directory = base_path.join("settings");
+            let environment_filename = format!("{}.json5", environment.as_str());
+            configuration_directory.join(environment_filename)
+        }
+    }
+}
+
+pub fn get_configuration(
+    config_file: Option<&std::path::Path>,
+    environment: &Environment,
+) -> Result<Settings, config::ConfigError> {
+    let config_file = config_file_path(config_file, environment);
 
     debug!("Config file: {config_file:?}");
 
     let settings = config::Config::builder()
-        .set_default("inbox_name", "INBOX")?
-        .set_default("storage_folder_name", "amcheck_storage")?
-        .set_default("days_back", i64::from(60))?
+        .set_default("inbox_name", default_inbox_name())?
+        .set_default("storage_folder_name", default_storage_folder_name())?
+        .set_default("days_back", default_days_back())?
         .set_default("gmail_delete_hack", false)?
-        .add_source(config::File::from(config_file))
+        .set_default("uid_chunk_size", default_uid_chunk_size() as u64)?
+        .set_default("parallel_accounts", false)?
+        .add_source(config::File::from(config_file.as_path()))
         // Add in settings from environment variables (with a prefix of AMCHECK and '__' as separator)
         // E.g. `AMCHECK_APPLICATION__PORT=5001 would set `Settings.application.port`
         .add_source(
@@ -292,7 +1489,131 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         )
         .build()?;
 
-    settings.try_deserialize::<Settings>()
+    let mut settings = settings.try_deserialize::<serde_json::Value>()?;
+    crate::includes::resolve(&mut settings, &config_file)?;
+    let expanded = crate::templates::expand(settings)?;
+
+    serde::Deserialize::deserialize(to_config_value(expanded))
+}
+
+// Going back through config::Config::try_from would be simpler, but it drops empty lists, which
+// matter to us (e.g. `filters: []`).  We still want a config::Value rather than deserializing
+// the JSON directly, since it's more forgiving about types: environment variables are all
+// strings, for one thing.
+fn to_config_value(value: serde_json::Value) -> config::Value {
+    let kind = match value {
+        serde_json::Value::Null => config::ValueKind::Nil,
+        serde_json::Value::Bool(x) => config::ValueKind::Boolean(x),
+        serde_json::Value::Number(x) => match (x.as_i64(), x.as_u64()) {
+            (Some(x), _) => config::ValueKind::I64(x),
+            (None, Some(x)) => config::ValueKind::U64(x),
+            (None, None) => config::ValueKind::Float(x.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(x) => config::ValueKind::String(x),
+        serde_json::Value::Array(x) => {
+            config::ValueKind::Array(x.into_iter().map(to_config_value).collect())
+        }
+        serde_json::Value::Object(x) => config::ValueKind::Table(
+            x.into_iter()
+                .map(|(key, value)| (key, to_config_value(value)))
+                .collect(),
+        ),
+    };
+
+    config::Value::new(None, kind)
+}
+
+/// A JSON Schema for the config file.  This is mostly just generated from `Settings`, but the
+/// templates are expanded before we get to that, so they have to be added in by hand.
+pub fn json_schema() -> schemars::Schema {
+    let mut schema = schemars::schema_for!(Settings);
+
+    let placeholder = serde_json::json!({ "$ref": "#/$defs/Placeholder" });
+
+    if let Some(serde_json::Value::Object(defs)) = schema.get_mut("$defs") {
+        // A template parameter can stand in for any field, not just strings
+        for def in defs.values_mut() {
+            let Some(serde_json::Value::Object(properties)) = def.get_mut("properties") else {
+                continue;
+            };
+            for property in properties.values_mut() {
+                if property.get("type") != Some(&serde_json::json!("string")) {
+                    *property = serde_json::json!({ "anyOf": [property, placeholder] });
+                }
+            }
+        }
+
+        // Or a whole filter or tree, as can a reference to a template
+        for def in ["Filter", "CheckerTree"] {
+            if let Some(serde_json::Value::Array(variants)) =
+                defs.get_mut(def).and_then(|x| x.get_mut("oneOf"))
+            {
+                variants.push(serde_json::json!({ "$ref": "#/$defs/Ref" }));
+                variants.push(placeholder.clone());
+            }
+        }
+
+        defs.insert(
+            "Placeholder".to_string(),
+            serde_json::json!({
+                "description": "A template parameter",
+                "type": "string",
+                "pattern": "^\\$\\{[^}]+\\}$",
+            }),
+        );
+        defs.insert(
+            "Ref".to_string(),
+            serde_json::json!({
+                "description": "A reference to one of the top-level filter_sets (inside a list of filters) or trees (anywhere else)",
+                "type": "object",
+                "properties": {
+                    "Ref": {
+                        "oneOf": [
+                            { "type": "string" },
+                            {
+                                "type": "object",
+                                "properties": {
+                                    "name": { "type": "string" },
+                                    "params": { "type": "object" },
+                                },
+                                "required": ["name"],
+                                "additionalProperties": false,
+                            },
+                        ],
+                    },
+                },
+                "required": ["Ref"],
+                "additionalProperties": false,
+            }),
+        );
+    }
+
+    if let Some(serde_json::Value::Object(properties)) = schema.get_mut("properties") {
+        properties.insert(
+            "include".to_string(),
+            serde_json::json!({
+                "description": "Files or directories of files to merge handlers, handler_sets, filter_sets and trees from, relative to this file",
+                "type": "array",
+                "items": { "type": "string" },
+            }),
+        );
+        properties.insert(
+            "filter_sets".to_string(),
+            serde_json::json!({
+                "type": "object",
+                "additionalProperties": { "type": "array", "items": { "$ref": "#/$defs/Filter" } },
+            }),
+        );
+        properties.insert(
+            "trees".to_string(),
+            serde_json::json!({
+                "type": "object",
+                "additionalProperties": { "$ref": "#/$defs/CheckerTree" },
+            }),
+        );
+    }
+
+    schema
 }
 
 /// The possible runtime environment for our application.
@@ -326,3 +1647,256 @@ impl TryFrom<String> for Environment {
         }
     }
 }
+
+impl std::str::FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.to_string().try_into()
+    }
+}
+
+#[cfg(test)]
+mod accounts_test {
+    use crate::configuration::Settings;
+    use secrecy::ExposeSecret;
+
+    // The settings in `extra`, on top of the defaults the config setup would have filled in
+    fn settings_with(extra: &str) -> Settings {
+        config::Config::builder()
+            .set_default("inbox_name", "INBOX")
+            .unwrap()
+            .set_default("storage_folder_name", "amcheck_storage")
+            .unwrap()
+            .set_default("days_back", 60)
+            .unwrap()
+            .set_default("gmail_delete_hack", false)
+            .unwrap()
+            .set_default("uid_chunk_size", 1000)
+            .unwrap()
+            .set_default("parallel_accounts", false)
+            .unwrap()
+            .add_source(config::File::from_str(
+                &format!("{{ {extra} }}"),
+                config::FileFormat::Json5,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn handler(name: &str) -> String {
+        format!(r#"{{ name: "{name}", filters: [], checker_tree: {{ Action: "Alert" }} }}"#)
+    }
+
+    fn error(settings: &Settings) -> String {
+        settings.accounts().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_flattening() {
+        let settings = settings_with(&format!(
+            r#"
+              imapserver: "imap.example.com",
+              login: "me",
+              password: "secret",
+              handlers: [ {} ],
+              envelope_cache_dir: "/var/cache/amcheck",
+              accounts: [
+                {{ name: "work", imapserver: "imap.work.com", login: "w", password: "p",
+                   inbox_name: "Inbox", days_back: 7, uid_chunk_size: 50 }},
+                {{ name: "lists", imapserver: "imap.lists.com", login: "l", password: "p",
+                   inbox_name: "Ignored", source_folders: [ "lists", "cron" ],
+                   storage_folder_name: "lists_storage", gmail_delete_hack: true }},
+              ],
+            "#,
+            handler("top")
+        ));
+
+        let accounts = settings.accounts().unwrap();
+        assert_eq!(
+            accounts.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+            vec!["default", "work", "lists"]
+        );
+
+        let default = &accounts[0];
+        assert_eq!(default.imapserver, "imap.example.com");
+        assert_eq!(default.password.expose_secret(), "secret");
+        assert_eq!(default.handlers[0].name, "top");
+        assert_eq!(default.source_folders, vec!["INBOX"]);
+
+        // Its own values where it has them, and the top-level ones where it doesn't
+        let work = &accounts[1];
+        assert!(work.handlers.is_empty());
+        assert_eq!(work.source_folders, vec!["Inbox"]);
+        assert_eq!(work.storage_folder_name, "amcheck_storage");
+        assert_eq!(work.days_back, 7);
+        assert_eq!(work.uid_chunk_size, 50);
+        assert!(!work.gmail_delete_hack);
+
+        // source_folders beats inbox_name
+        let lists = &accounts[2];
+        assert_eq!(lists.source_folders, vec!["lists", "cron"]);
+        assert_eq!(lists.storage_folder_name, "lists_storage");
+        assert_eq!(lists.days_back, 60);
+        assert!(lists.gmail_delete_hack);
+
+        for account in &accounts {
+            assert_eq!(
+                account.envelope_cache_dir.as_deref(),
+                Some(std::path::Path::new("/var/cache/amcheck"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_top_level_only() {
+        let settings = settings_with(
+            r#"imapserver: "imap.example.com", login: "me", password: "secret",
+               source_folders: [ "INBOX", "cron" ]"#,
+        );
+
+        let accounts = settings.accounts().unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].source_folders, vec!["INBOX", "cron"]);
+
+        assert!(error(&settings_with(
+            r#"imapserver: "imap.example.com", login: "me""#
+        ))
+        .contains("must be given together"));
+        assert!(error(&settings_with("")).contains("No accounts configured"));
+        assert!(
+            error(&settings_with(&format!("handlers: [ {} ]", handler("top"))))
+                .contains("Top-level handlers are only used")
+        );
+    }
+
+    #[test]
+    fn test_handler_sets() {
+        let settings = settings_with(&format!(
+            r#"
+              handler_sets: {{ common: [ {}, {} ], extra: [ {} ] }},
+              accounts: [
+                {{ name: "work", imapserver: "imap.work.com", login: "w", password: "p",
+                   handlers: [ {} ], handler_sets: [ "extra", "common" ] }},
+                {{ name: "home", imapserver: "imap.home.com", login: "h", password: "p",
+                   handler_sets: [ "common" ] }},
+              ],
+            "#,
+            handler("common 1"),
+            handler("common 2"),
+            handler("extra"),
+            handler("own"),
+        ));
+
+        let accounts = settings.accounts().unwrap();
+        let names = |i: usize| {
+            accounts[i]
+                .handlers
+                .iter()
+                .map(|x| x.name.clone())
+                .collect::<Vec<_>>()
+        };
+        // The account's own first, then the sets in the order it asked for them
+        assert_eq!(names(0), vec!["own", "extra", "common 1", "common 2"]);
+        assert_eq!(names(1), vec!["common 1", "common 2"]);
+
+        let settings = settings_with(
+            r#"accounts: [ { name: "work", imapserver: "imap.work.com", login: "w",
+                             password: "p", handler_sets: [ "missing" ] } ]"#,
+        );
+        assert_eq!(
+            error(&settings),
+            "Account 'work' refers to unknown handler set 'missing'"
+        );
+    }
+
+    #[test]
+    fn test_source_folders() {
+        let handler = |name: &str, folders: &str| {
+            format!(
+                r#"{{ name: "{name}", filters: [], checker_tree: {{ Action: "Alert" }},
+                      source_folders: [ {folders} ] }}"#
+            )
+        };
+        let settings = settings_with(&format!(
+            r#"accounts: [ {{ name: "work", imapserver: "imap.work.com", login: "w",
+                              password: "p", source_folders: [ "INBOX", "cron" ],
+                              handlers: [ {}, {}, {}, {} ] }} ]"#,
+            handler("everywhere", ""),
+            handler("alerts", r#""alerts""#),
+            handler("cron and lists", r#""cron", "lists""#),
+            handler("lists again", r#""lists", "alerts""#),
+        ));
+
+        let accounts = settings.accounts().unwrap();
+        let account = &accounts[0];
+        // The account's own first, then each handler's new ones, once each
+        assert_eq!(
+            account.all_source_folders(),
+            vec!["INBOX", "cron", "alerts", "lists"]
+        );
+
+        let watched = |name: &str| {
+            let handler = account.handlers.iter().find(|x| x.name == name).unwrap();
+            account
+                .all_source_folders()
+                .into_iter()
+                .filter(|x| account.handler_watches(handler, x))
+                .collect::<Vec<_>>()
+        };
+        // Without source_folders of its own, a handler watches just the account's
+        assert_eq!(watched("everywhere"), vec!["INBOX", "cron"]);
+        // With them, just those, whether or not the account sweeps them anyway
+        assert_eq!(watched("alerts"), vec!["alerts"]);
+        assert_eq!(watched("cron and lists"), vec!["cron", "lists"]);
+        assert_eq!(watched("lists again"), vec!["alerts", "lists"]);
+    }
+
+    #[test]
+    fn test_checks() {
+        let account = |fields: &str| {
+            format!(
+                r#"{{ name: "work", imapserver: "imap.work.com", login: "w", password: "p", {fields} }}"#
+            )
+        };
+
+        assert_eq!(
+            error(&settings_with(&format!(
+                "accounts: [ {}, {} ]",
+                account(""),
+                account("")
+            ))),
+            "Account name 'work' is used more than once"
+        );
+
+        assert_eq!(
+            error(&settings_with(&format!(
+                "accounts: [ {} ]",
+                account(r#"source_folders: [ "INBOX", "amcheck_storage" ]"#)
+            ))),
+            "Account 'work' uses the storage folder 'amcheck_storage' as a source folder"
+        );
+
+        // Handlers' folders count too
+        let handler = r#"{ name: "cron", filters: [], checker_tree: { Action: "Alert" },
+                           source_folders: [ "cron" ], storage_folder_name: "INBOX" }"#;
+        assert_eq!(
+            error(&settings_with(&format!(
+                "accounts: [ {} ]",
+                account(&format!("handlers: [ {handler} ]"))
+            ))),
+            "Account 'work' uses the storage folder 'INBOX' as a source folder"
+        );
+
+        let handler = r#"{ name: "cron", filters: [], checker_tree: { Action: "Alert" },
+                           source_folders: [ "cron" ], storage_folder_name: "cron_storage" }"#;
+        assert!(settings_with(&format!(
+            "accounts: [ {} ]",
+            account(&format!("handlers: [ {handler} ]"))
+        ))
+        .accounts()
+        .is_ok());
+    }
+}
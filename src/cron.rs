@@ -0,0 +1,266 @@
+// Just enough of cron's schedule format for working out when a cron job should have run, and so
+// when we should have seen mail from it.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::clock::Clock;
+
+/// A standard five-field cron schedule: minute, hour, day of month, month and day of week, each
+/// of which can be `*`, a number, a range (`1-5`), a list (`1,15`), any of those with a step
+/// (`*/15`, `0-30/10`), or (for months and days of the week) a three-letter name.
+///
+/// As in cron, if both the day of month and the day of week are restricted (i.e. don't start
+/// with `*`), a day matches if either of them does.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    source: String,
+    // Bit n set means n is allowed
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Schedule {
+    /// Every time this schedule fires from `start` to `end` inclusive, in order, with the
+    /// schedule in the clock's local time.
+    pub fn instants_between(
+        &self,
+        start: time::OffsetDateTime,
+        end: time::OffsetDateTime,
+        clock: &Clock,
+    ) -> Vec<time::OffsetDateTime> {
+        let mut instants = Vec::new();
+
+        let last = clock.to_local(end).date();
+        let mut date = Some(clock.to_local(start).date());
+        while let Some(day) = date.filter(|x| *x <= last) {
+            if self.matches_date(day) {
+                for hour in bits(self.hours) {
+                    for minute in bits(self.minutes) {
+                        let Ok(time) = time::Time::from_hms(hour, minute, 0) else {
+                            continue;
+                        };
+                        let instant = clock.local(day.with_time(time));
+                        if start <= instant && instant <= end {
+                            instants.push(instant);
+                        }
+                    }
+                }
+            }
+            date = day.next_day();
+        }
+
+        instants
+    }
+
+    fn matches_date(&self, date: time::Date) -> bool {
+        if !has(self.months, u8::from(date.month())) {
+            return false;
+        }
+
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().number_days_from_sunday());
+        if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+fn has(set: u64, value: u8) -> bool {
+    set & (1 << value) != 0
+}
+
+fn bits(set: u64) -> impl Iterator<Item = u8> {
+    (0..64).filter(move |x| has(set, *x))
+}
+
+fn parse_field(field: &str, min: u8, max: u8, names: &[&str]) -> Result<u64, String> {
+    let invalid = || format!("'{field}' isn't valid here; it must be between {min} and {max}");
+
+    let value = |text: &str| -> Result<u8, String> {
+        let lower = text.to_ascii_lowercase();
+        match names.iter().position(|x| *x == lower) {
+            Some(index) => Ok(min + u8::try_from(index).map_err(|_| invalid())?),
+            None => text
+                .parse::<u8>()
+                .ok()
+                .filter(|x| (min..=max).contains(x))
+                .ok_or_else(invalid),
+        }
+    };
+
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u8>().map_err(|_| invalid())?)),
+            None => (part, None),
+        };
+
+        let (first, last) = match (range, range.split_once('-')) {
+            ("*", _) => (min, max),
+            (_, Some((first, last))) => (value(first)?, value(last)?),
+            // Like cron, "5/15" means "every 15 starting at 5"
+            (_, None) if step.is_some() => (value(range)?, max),
+            (_, None) => (value(range)?, value(range)?),
+        };
+
+        let step = step.unwrap_or(1);
+        if step == 0 || first > last {
+            return Err(invalid());
+        }
+
+        for x in (first..=last).step_by(usize::from(step)) {
+            set |= 1 << x;
+        }
+    }
+
+    Ok(set)
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "'{s}' isn't a cron schedule; it needs five fields (minute, hour, day of month, month, day of week)"
+            ));
+        };
+
+        let context = |name: &'static str| move |e: String| format!("In the {name} of '{s}': {e}");
+
+        let mut weekday_set =
+            parse_field(weekdays, 0, 7, &WEEKDAYS).map_err(context("day of week"))?;
+        // 7 is another name for Sunday
+        if has(weekday_set, 7) {
+            weekday_set = (weekday_set | 1) & !(1 << 7);
+        }
+
+        Ok(Schedule {
+            source: s.to_string(),
+            minutes: parse_field(minutes, 0, 59, &[]).map_err(context("minute"))?,
+            hours: parse_field(hours, 0, 23, &[]).map_err(context("hour"))?,
+            days: parse_field(days, 1, 31, &[]).map_err(context("day of month"))?,
+            months: parse_field(months, 1, 12, &MONTHS).map_err(context("month"))?,
+            weekdays: weekday_set,
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.source
+    }
+}
+
+#[cfg(test)]
+mod cron_test {
+    use super::Schedule;
+    use crate::clock::Clock;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_weekdays() {
+        let schedule: Schedule = "0 2 * * 1-5".parse().unwrap();
+
+        // Friday to the following Wednesday
+        let instants = schedule.instants_between(
+            datetime!(2024-03-08 00:00 -8),
+            datetime!(2024-03-13 02:00 -8),
+            &Clock::fixed(datetime!(2024-03-13 02:00 -8)),
+        );
+
+        assert_eq!(
+            instants,
+            vec![
+                datetime!(2024-03-08 02:00 -8),
+                datetime!(2024-03-11 02:00 -8),
+                datetime!(2024-03-12 02:00 -8),
+                datetime!(2024-03-13 02:00 -8),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fields() {
+        let schedule: Schedule = "*/20 9-10 1,15 * sun".parse().unwrap();
+
+        // Both day fields are restricted, so either will do: the 1st, 3rd (a Sunday) and 15th
+        let instants = schedule.instants_between(
+            datetime!(2024-03-01 00:00 UTC),
+            datetime!(2024-03-31 00:00 UTC),
+            &Clock::fixed(datetime!(2024-03-31 00:00 UTC)),
+        );
+
+        assert_eq!(instants.len(), 6 * 6);
+        assert_eq!(instants[0], datetime!(2024-03-01 09:00 UTC));
+        assert_eq!(instants[5], datetime!(2024-03-01 10:40 UTC));
+        assert_eq!(instants[6], datetime!(2024-03-03 09:00 UTC));
+        assert_eq!(instants[12], datetime!(2024-03-10 09:00 UTC));
+        assert_eq!(instants[18], datetime!(2024-03-15 09:00 UTC));
+
+        let schedule: Schedule = "30 12 1 JAN,jul 7".parse().unwrap();
+        assert_eq!(
+            schedule
+                .instants_between(
+                    datetime!(2024-01-01 00:00 UTC),
+                    datetime!(2024-12-31 00:00 UTC),
+                    &Clock::fixed(datetime!(2024-12-31 00:00 UTC)),
+                )
+                .len(),
+            // Two 1sts, plus every Sunday in January and July
+            2 + 4 + 4
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        for bad in [
+            "",
+            "0 2 * *",
+            "0 2 * * 1 1",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(bad.parse::<Schedule>().is_err(), "{bad}");
+        }
+    }
+}
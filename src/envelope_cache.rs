@@ -0,0 +1,197 @@
+// Envelopes (and whatever else we work out from a FETCH) of mails we've already seen, kept on disk
+// between runs, so that a check only has to fetch the mails that arrived since the last one.
+//
+// A UID only ever means the same mail for as long as the folder's UIDVALIDITY stays the same, so
+// each folder's entries are only used if it still has the UIDVALIDITY they were cached under.
+// Each folder also remembers its HIGHESTMODSEQ at the time, if the server has CONDSTORE, so that
+// with QRESYNC we can ask the server what's changed since then instead of searching the folder.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::my_imap_wrapper::Uid;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: DeserializeOwned"))]
+struct FolderCache<T> {
+    uid_validity: u32,
+    #[serde(default)]
+    highest_mod_seq: Option<u64>,
+    mails: Vec<(Uid, T)>,
+}
+
+/// One account's cache, in one file.
+#[derive(Debug)]
+pub struct EnvelopeCache<T> {
+    path: PathBuf,
+    folders: HashMap<String, FolderCache<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned> EnvelopeCache<T> {
+    /// The cache for the given account in `dir`.  A missing or unreadable cache file just means
+    /// starting again with an empty cache.
+    pub fn load(dir: &Path, account_name: &str) -> EnvelopeCache<T> {
+        // Account names are free text, so keep them from going anywhere but `dir`; anything that
+        // isn't a letter, digit or '-' is escaped as '_' and its bytes in hex, so that no two
+        // names end up with the same file
+        let file_name: String = account_name
+            .chars()
+            .map(|x| {
+                if x.is_ascii_alphanumeric() || x == '-' {
+                    x.to_string()
+                } else {
+                    let mut bytes = [0; 4];
+                    x.encode_utf8(&mut bytes)
+                        .bytes()
+                        .map(|x| format!("_{x:02X}"))
+                        .collect()
+                }
+            })
+            .collect();
+        let path = dir.join(format!("{file_name}.json"));
+
+        let contents = match std::fs::read(&path) {
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            result => result.map(Some).map_err(|x| x.to_string()),
+        };
+        let folders = contents
+            .and_then(|x| match x {
+                Some(contents) => serde_json::from_slice(&contents).map_err(|x| x.to_string()),
+                None => Ok(HashMap::new()),
+            })
+            .unwrap_or_else(|error| {
+                warn!(
+                    "Ignoring the envelope cache in {}, which couldn't be read: {error}",
+                    path.display()
+                );
+                HashMap::new()
+            });
+
+        EnvelopeCache { path, folders }
+    }
+
+    /// Takes out the cached mails for the folder, and its HIGHESTMODSEQ when they were cached, if
+    /// it still has the same UIDVALIDITY.
+    pub fn take(&mut self, folder: &str, uid_validity: u32) -> (HashMap<Uid, T>, Option<u64>) {
+        match self.folders.remove(folder) {
+            Some(cached) if cached.uid_validity == uid_validity => {
+                debug!("{} mails cached for {folder}", cached.mails.len());
+                (cached.mails.into_iter().collect(), cached.highest_mod_seq)
+            }
+            Some(_) => {
+                info!("UIDVALIDITY of {folder} has changed; throwing away its cached mails");
+                (HashMap::new(), None)
+            }
+            None => (HashMap::new(), None),
+        }
+    }
+
+    /// Replaces everything cached for the folder; UIDs that aren't in `mails` any more are gone
+    /// from the folder, so they're dropped.
+    pub fn put(
+        &mut self,
+        folder: &str,
+        uid_validity: u32,
+        highest_mod_seq: Option<u64>,
+        mails: Vec<(Uid, T)>,
+    ) {
+        self.folders.insert(
+            folder.to_string(),
+            FolderCache {
+                uid_validity,
+                highest_mod_seq,
+                mails,
+            },
+        );
+    }
+
+    /// Writes the cache out.  It goes to a temporary file first, so that a run that dies part
+    /// way through doesn't leave a broken cache behind.
+    pub fn save(&self) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let temporary = self.path.with_extension("json.tmp");
+        std::fs::write(&temporary, serde_json::to_vec(&self.folders)?)?;
+        std::fs::rename(&temporary, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod envelope_cache_test {
+    use super::EnvelopeCache;
+    use crate::my_imap_wrapper::Uid;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = std::env::temp_dir().join(format!("amcheck-cache-{}", std::process::id()));
+
+        let mut cache: EnvelopeCache<String> = EnvelopeCache::load(&dir, "work/mail");
+        assert!(cache.take("amcheck_storage", 7).0.is_empty());
+        cache.put(
+            "amcheck_storage",
+            7,
+            Some(1234),
+            vec![
+                (Uid::from(1), "one".to_string()),
+                (Uid::from(2), "two".to_string()),
+            ],
+        );
+        cache.put("other", 3, None, vec![(Uid::from(5), "five".to_string())]);
+        cache.save().unwrap();
+
+        let mut cache: EnvelopeCache<String> = EnvelopeCache::load(&dir, "work/mail");
+        let file_exists = dir.join("work_2Fmail.json").exists();
+        let (storage, highest_mod_seq) = cache.take("amcheck_storage", 7);
+        // UIDVALIDITY has changed
+        let other = cache.take("other", 4);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(file_exists);
+        assert_eq!(storage.len(), 2);
+        assert_eq!(storage[&Uid::from(2)], "two");
+        assert_eq!(highest_mod_seq, Some(1234));
+        assert_eq!(other, (std::collections::HashMap::new(), None));
+    }
+
+    #[test]
+    fn test_file_names() {
+        let dir = std::env::temp_dir().join(format!("amcheck-names-{}", std::process::id()));
+
+        // These used to share a file
+        for name in ["work/mail", "work_mail", "work mail"] {
+            let mut cache: EnvelopeCache<String> = EnvelopeCache::load(&dir, name);
+            cache.put(name, 1, None, vec![(Uid::from(1), name.to_string())]);
+            cache.save().unwrap();
+        }
+
+        let mut names = Vec::new();
+        for name in ["work/mail", "work_mail", "work mail"] {
+            let mut cache: EnvelopeCache<String> = EnvelopeCache::load(&dir, name);
+            names.push(cache.take(name, 1).0.remove(&Uid::from(1)));
+        }
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            names,
+            vec![
+                Some("work/mail".to_string()),
+                Some("work_mail".to_string()),
+                Some("work mail".to_string())
+            ]
+        );
+        assert_eq!(
+            files,
+            vec!["work_20mail.json", "work_2Fmail.json", "work_5Fmail.json"]
+        );
+    }
+}
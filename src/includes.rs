@@ -0,0 +1,196 @@
+// Splitting the config across files: the top-level `include` lists files (or directories full of
+// files) whose handlers, handler sets, filter sets and trees get merged into the main config.
+//
+// Like the templates, this happens on the untyped config, before it's deserialized into
+// `Settings`; it happens before the templates are expanded, so that any file can use templates
+// defined in any other.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value};
+
+/// Merges everything the given config (which was read from `config_file`) includes into it,
+/// removing the `include` along the way.
+///
+/// Handlers from included files go after the ones that are already there, in order: the main
+/// file, then each included file in the order they're listed (with a directory's files in
+/// filename order), with each file immediately followed by anything it includes in turn.  The
+/// named things (handler sets, filter sets and trees) can't be defined in more than one file.
+pub fn resolve(settings: &mut Value, config_file: &Path) -> Result<(), config::ConfigError> {
+    let mut visited = HashSet::from([canonicalize(config_file)?]);
+
+    for path in take_includes(settings, config_file)? {
+        merge_file(settings, &path, &mut visited)?;
+    }
+
+    Ok(())
+}
+
+/// The files the given config (which was read from `config_file`) includes directly, in order.
+pub fn included_files(
+    settings: &Value,
+    config_file: &Path,
+) -> Result<Vec<PathBuf>, config::ConfigError> {
+    let Some(include) = settings.get("include") else {
+        return Ok(Vec::new());
+    };
+
+    let invalid = || {
+        config::ConfigError::Message(format!(
+            "{}: include must be a list of file or directory names",
+            config_file.display()
+        ))
+    };
+
+    // Relative names are relative to the file they're in, not to wherever we're running
+    let base = config_file.parent().unwrap_or(Path::new(""));
+
+    let mut files = Vec::new();
+    for name in include.as_array().ok_or_else(invalid)? {
+        let path = base.join(name.as_str().ok_or_else(invalid)?);
+
+        if path.is_dir() {
+            let mut dir_files = Vec::new();
+            for entry in std::fs::read_dir(&path).map_err(|x| io_error(&path, &x))? {
+                let entry_path = entry.map_err(|x| io_error(&path, &x))?.path();
+                let file_name = entry_path.file_name().unwrap_or_default().to_string_lossy();
+                // Skip editor droppings
+                if entry_path.is_file() && !file_name.starts_with('.') && !file_name.ends_with('~')
+                {
+                    dir_files.push(entry_path);
+                }
+            }
+            dir_files.sort();
+            files.extend(dir_files);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+fn take_includes(
+    settings: &mut Value,
+    config_file: &Path,
+) -> Result<Vec<PathBuf>, config::ConfigError> {
+    let files = included_files(settings, config_file)?;
+    if let Some(map) = settings.as_object_mut() {
+        map.remove("include");
+    }
+    Ok(files)
+}
+
+fn merge_file(
+    settings: &mut Value,
+    path: &Path,
+    visited: &mut HashSet<PathBuf>,
+) -> Result<(), config::ConfigError> {
+    if !visited.insert(canonicalize(path)?) {
+        return Err(config::ConfigError::Message(format!(
+            "{} is included more than once",
+            path.display()
+        )));
+    }
+
+    let mut included = config::Config::builder()
+        .add_source(config::File::from(path))
+        .build()?
+        .try_deserialize::<Value>()?;
+    let nested = take_includes(&mut included, path)?;
+
+    let Value::Object(included) = included else {
+        return Ok(());
+    };
+    let Some(settings_map) = settings.as_object_mut() else {
+        return Ok(());
+    };
+
+    for (key, value) in included {
+        match (key.as_str(), value) {
+            ("handlers", Value::Array(handlers)) => {
+                match settings_map
+                    .entry(key)
+                    .or_insert_with(|| Value::Array(Vec::new()))
+                {
+                    Value::Array(existing) => existing.extend(handlers),
+                    _ => return Err(not_mergeable(path, "handlers")),
+                }
+            }
+            ("handler_sets" | "filter_sets" | "trees", Value::Object(named)) => {
+                let Value::Object(existing) = settings_map
+                    .entry(key.clone())
+                    .or_insert_with(|| Value::Object(Map::new()))
+                else {
+                    return Err(not_mergeable(path, &key));
+                };
+                for (name, value) in named {
+                    if existing.contains_key(&name) {
+                        return Err(config::ConfigError::Message(format!(
+                            "{}: there's already an entry in {key} called '{name}'",
+                            path.display()
+                        )));
+                    }
+                    existing.insert(name, value);
+                }
+            }
+            (key, _) => return Err(not_mergeable(path, key)),
+        }
+    }
+
+    for nested_path in nested {
+        merge_file(settings, &nested_path, visited)?;
+    }
+
+    Ok(())
+}
+
+fn not_mergeable(path: &Path, key: &str) -> config::ConfigError {
+    config::ConfigError::Message(format!(
+        "{}: included files can only have handlers (a list), handler_sets, filter_sets and trees (maps), and include, not {key}",
+        path.display()
+    ))
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf, config::ConfigError> {
+    path.canonicalize().map_err(|x| io_error(path, &x))
+}
+
+fn io_error(path: &Path, error: &std::io::Error) -> config::ConfigError {
+    config::ConfigError::Message(format!("{}: {error}", path.display()))
+}
+
+#[cfg(test)]
+mod includes_test {
+    use super::resolve;
+    use serde_json::json;
+
+    #[test]
+    fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("amcheck-includes-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("handlers.d")).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        write("main.json5", "");
+        write("handlers.d/20-b.json5", r#"{ handlers: [ "b" ] }"#);
+        write(
+            "handlers.d/10-a.json5",
+            r#"{ handlers: [ "a" ], include: [ "../nested.json5" ] }"#,
+        );
+        write("handlers.d/.10-a.json5.swp", "not json5");
+        write(
+            "nested.json5",
+            r#"{ handlers: [ "nested" ], trees: { t: "Stop" } }"#,
+        );
+
+        let mut settings = json!({ "include": [ "handlers.d" ], "handlers": [ "main" ] });
+        let result = resolve(&mut settings, &dir.join("main.json5"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(
+            settings,
+            json!({ "handlers": [ "main", "a", "nested", "b" ], "trees": { "t": "Stop" } })
+        );
+    }
+}
@@ -1,2 +1,10 @@
+pub mod cli;
+pub mod clock;
 pub mod configuration;
+pub mod cron;
+pub mod envelope_cache;
+pub mod includes;
 pub mod my_imap_wrapper;
+pub mod search;
+pub mod templates;
+pub mod validate;
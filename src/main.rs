@@ -1,30 +1,60 @@
 #![warn(clippy::all, clippy::pedantic)]
-use core::panic;
 use secrecy::ExposeSecret;
 use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap};
 
+use amcheck::cli::{Cli, Command, LogFormat};
+use amcheck::clock::Clock;
 use amcheck::configuration::{
-    get_configuration, get_environment, Action, CheckerTree, Filter, Handler, MatcherPart,
+    config_file_path, get_configuration, json_schema, Account, Action, BodySearch, Capture,
+    CaptureField, CheckerTree, Environment, Filter, Handler, Interval, MatcherPart, Selection,
 };
+use amcheck::envelope_cache::EnvelopeCache;
 
 use amcheck::configuration::DateEmpty;
 use amcheck::configuration::MatchEmpty;
-use amcheck::my_imap_wrapper::{my_uid_search, Uid};
+use amcheck::my_imap_wrapper::{
+    my_enable, my_uid_changed_since, my_uid_mv, my_uid_search, uid_chunks, uid_set, Uid,
+};
+use amcheck::search::{Literals, Search};
+use amcheck::validate::{lint, locate_error};
 
-use error_stack::{Result, ResultExt};
+use error_stack::{Report, Result, ResultExt};
+use mailparse::MailHeaderMap;
 use thiserror::Error;
 
+use clap::{CommandFactory, Parser};
 use tracing::{debug, enabled, error, info, trace, warn, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct Mail {
     pub uid: Uid,
     pub subject: String,
     pub from_addr: String,
+    #[serde(with = "time::serde::rfc3339")]
     pub date: time::OffsetDateTime,
+    // When the server got it, which is what its date searches go by; caches from before this was
+    // fetched don't have it
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub internal_date: Option<time::OffsetDateTime>,
+    pub message_id: Option<String>,
+    // In bytes, as the server counts it; None if it didn't say
+    pub size: Option<u32>,
+    // The leaves of the MIME structure, in order; just the one for a mail that isn't multipart
+    pub parts: Vec<MailPart>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct MailPart {
+    // Lower case, e.g. "text/plain"
+    pub mime_type: String,
+    pub filename: Option<String>,
+    // Either marked as an attachment, or has a filename
+    pub attachment: bool,
 }
 
 #[derive(Debug, Error)]
@@ -37,25 +67,262 @@ enum MyError {
     DateFormatting,
     #[error("Could not subtract {0} days from now.")]
     DateSubtraction(i64),
+    #[error("Could not work out the date for DateCheck cutoff {0}.")]
+    DateCutoff(String),
+    #[error("Could not work out the lookback window for ExpectSchedule {0}.")]
+    ScheduleWindow(String),
+    #[error("{0} account(s) failed")]
+    Accounts(usize),
+    #[error("Account run panicked")]
+    Panicked,
+    #[error("Invalid configuration")]
+    Config,
+    #[error("{0} problem(s) found in the configuration")]
+    Lint(usize),
+}
+
+// What happened during the run against one account; when there's more than one account, these
+// get printed together at the end.
+#[derive(Debug, Default)]
+struct Summary {
+    moved: usize,
+    deleted: usize,
+    passed: usize,
+    failed: Vec<String>,
+}
+
+// The state shared by everything in a single run against a single account.
+struct Context<'a> {
+    imap_session: &'a mut imap::Session<Box<dyn imap::ImapConnection>>,
+    noop: bool,
+    gmail_delete_hack: bool,
+    uid_chunk_size: usize,
+    clock: &'a Clock,
+    summary: Summary,
+    // Mails the move phase put into each storage folder, already parsed and with their new UIDs,
+    // so a check in the same session doesn't have to fetch them again
+    moved_mails: HashMap<String, Vec<Mail>>,
+    // Things worked out about particular mails on the way down the current handler's checker tree
+    // (like the values ExtractCheck pulled out), for the details of any Alert
+    notes: HashMap<Uid, Vec<String>>,
+    // Things worked out about the whole set of mails on the way down to the current node (like
+    // AggregateCheck's result), likewise
+    labels: Vec<String>,
+    // Which kinds of literal the server lets us send in searches
+    literals: Literals,
+    // Whether QRESYNC is on, so folders in the envelope cache can be synced incrementally
+    qresync: bool,
+    // The folder currently selected
+    folder: String,
+    // Whole mails fetched so far this session, by folder and UID, so that however many checks
+    // look at a mail's body, it only gets fetched once
+    bodies: HashMap<(String, Uid), Vec<u8>>,
+    // Mails seen in earlier runs, if there's an envelope cache
+    envelope_cache: Option<EnvelopeCache<Mail>>,
 }
 
 #[tracing::instrument]
 fn main() -> Result<(), MyError> {
-    tracing_subscriber::fmt()
-        .with_level(true)
-        .with_target(true)
-        .with_env_filter(EnvFilter::from_default_env())
-        .pretty()
-        .init();
+    let cli = Cli::parse();
+
+    init_logging(&cli);
+
+    if cli.command == Command::Validate {
+        return validate(&cli);
+    }
+
+    if cli.command == Command::Schema {
+        let schema = json_schema();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&schema).expect("Failed to serialize the schema.")
+        );
+        return Ok(());
+    }
 
-    let settings = get_configuration().expect("Failed to read configuration.");
+    let settings = get_configuration(cli.config.as_deref(), &cli.environment)
+        .expect("Failed to read configuration.");
 
     debug!("Settings: {:#?}", settings);
 
-    let args: Vec<String> = env::args().collect();
+    let mut accounts = settings.accounts().expect("Failed to read configuration.");
+
+    if !cli.handlers.is_empty() {
+        for name in &cli.handlers {
+            if !accounts
+                .iter()
+                .flat_map(|x| &x.handlers)
+                .any(|x| &x.name == name)
+            {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::InvalidValue,
+                        format!("There's no handler named '{name}' in the configuration"),
+                    )
+                    .exit();
+            }
+        }
+
+        for account in &mut accounts {
+            account.handlers.retain(|x| cli.handlers.contains(&x.name));
+        }
+        // No point talking to a server we've got nothing to do on
+        accounts.retain(|x| !x.handlers.is_empty());
+    }
+
+    // This has to happen before we start any threads, because the time crate refuses to work out
+    // the local offset once the process is multi-threaded.
+    let clock = Clock::new(cli.now, settings.timezone)
+        .change_context(MyError::DateFormatting)
+        .attach_printable("Can't work out the local time zone; set `timezone` in the config.")?;
+    debug!("Running as of {}", clock.now());
+
+    let mut results: Vec<(&str, Result<Summary, MyError>)> =
+        if settings.parallel_accounts && accounts.len() > 1 {
+            std::thread::scope(|scope| {
+                let threads = accounts
+                    .iter()
+                    .map(|account| {
+                        (
+                            account.name.as_str(),
+                            scope.spawn(|| run_account(account, &cli, &clock)),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                threads
+                    .into_iter()
+                    .map(|(name, thread)| (name, thread.join().unwrap_or_else(panicked)))
+                    .collect()
+            })
+        } else {
+            accounts
+                .iter()
+                .map(|account| {
+                    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        run_account(account, &cli, &clock)
+                    }));
+                    (account.name.as_str(), result.unwrap_or_else(panicked))
+                })
+                .collect()
+        };
+
+    // With just the one account, there's nothing to combine
+    if results.len() == 1 {
+        let (_, result) = results.remove(0);
+        return result.map(|_| ());
+    }
+
+    let mut failures = 0;
+    for (name, result) in &results {
+        match result {
+            Ok(summary) => {
+                info!(
+                    "Account '{name}': {} mails moved, {} mails deleted, {} checks passed, {} checks failed",
+                    summary.moved,
+                    summary.deleted,
+                    summary.passed,
+                    summary.failed.len()
+                );
+                for check in &summary.failed {
+                    warn!("Account '{name}': CHECK FAILED for check '{check}'");
+                }
+            }
+            Err(err) => {
+                failures += 1;
+                error!("Account '{name}' failed: {err:?}");
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(MyError::Accounts(failures).into());
+    }
+
+    Ok(())
+}
+
+// A panic in one account's run, as that account's result, so that it doesn't stop the others or
+// the report at the end.  The panic itself has already been printed by then.
+fn panicked(payload: Box<dyn std::any::Any + Send>) -> Result<Summary, MyError> {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|x| (*x).to_string())
+            .unwrap_or_default(),
+    };
+
+    Err(Report::new(MyError::Panicked).attach_printable(message))
+}
+
+fn init_logging(cli: &Cli) {
+    let filter = match cli.verbose {
+        0 => EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy(),
+        1 => EnvFilter::new("debug"),
+        _ => EnvFilter::new("trace"),
+    };
+
+    match cli.log_format {
+        LogFormat::Pretty => tracing_subscriber::fmt()
+            .with_level(true)
+            .with_target(true)
+            .with_env_filter(filter)
+            .pretty()
+            .init(),
+        LogFormat::Compact => tracing_subscriber::fmt()
+            .with_level(true)
+            .with_target(true)
+            .with_env_filter(filter)
+            .compact()
+            .init(),
+        LogFormat::Logfmt => tracing_subscriber::registry()
+            .with(filter)
+            .with(tracing_logfmt::layer())
+            .init(),
+    }
+}
+
+// Reports everything we can find wrong with the config file, without connecting to anything.
+fn validate(cli: &Cli) -> Result<(), MyError> {
+    let config_file = config_file_path(cli.config.as_deref(), &cli.environment);
+
+    // This gets syntax and structural errors, with positions
+    if let Err(problem) = locate_error(&config_file) {
+        error!("{problem}");
+        return Err(MyError::Config.into());
+    }
+
+    // This gets everything else that would stop us running
+    let accounts =
+        match get_configuration(Some(&config_file), &cli.environment).and_then(|x| x.accounts()) {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                error!("{}: {e}", config_file.display());
+                return Err(MyError::Config.into());
+            }
+        };
+
+    // And this gets things that will run, but probably not the way you meant
+    let problems: Vec<String> = accounts.iter().flat_map(lint).collect();
+    for problem in &problems {
+        warn!("{problem}");
+    }
+
+    if problems.is_empty() {
+        info!("{} looks good.", config_file.display());
+        Ok(())
+    } else {
+        Err(MyError::Lint(problems.len()).into())
+    }
+}
 
-    let mut client = imap::ClientBuilder::new(settings.imapserver, 993);
-    if get_environment() == amcheck::configuration::Environment::Test {
+#[tracing::instrument(skip_all, fields(account = account.name))]
+fn run_account(account: &Account, cli: &Cli, clock: &Clock) -> Result<Summary, MyError> {
+    let mut client = imap::ClientBuilder::new(&account.imapserver, 993);
+    if cli.environment == Environment::Test {
         // DANGER: do not use in prod!
         client = client.danger_skip_tls_verify(true);
     }
@@ -64,60 +331,69 @@ fn main() -> Result<(), MyError> {
     // The client we have here is unauthenticated;
     // to do anything useful with the e-mails, we need to log in
     let mut imap_session = client
-        .login(settings.login, settings.password.expose_secret())
-        .expect("Can't authenticate.");
+        .login(&account.login, account.password.expose_secret())
+        .map_err(|(err, _)| err)
+        .change_context(MyError::Imap)
+        .attach_printable("Can't authenticate.")?;
 
     if enabled!(Level::TRACE) {
         // At trace level, show all communications
         imap_session.debug = true;
     }
 
-    match args[1].as_str() {
-        "move" => {
-            move_to_storage(
-                &mut imap_session,
-                settings.handlers,
-                &settings.inbox_name,
-                &settings.storage_folder_name,
-                settings.days_back,
-                false,
-            )?;
-        }
-        "move_noop" => {
-            move_to_storage(
-                &mut imap_session,
-                settings.handlers,
-                &settings.inbox_name,
-                &settings.storage_folder_name,
-                settings.days_back,
-                true,
-            )?;
-        }
-        "check" => {
-            check_storage(
-                &mut imap_session,
-                settings.handlers,
-                &settings.storage_folder_name,
-                false,
-                settings.gmail_delete_hack,
-            )?;
-        }
-        "check_noop" => {
-            check_storage(
-                &mut imap_session,
-                settings.handlers,
-                &settings.storage_folder_name,
-                true,
-                settings.gmail_delete_hack,
-            )?;
-        }
-        _ => panic!("Sole argument must be either 'move' or 'check'."),
+    let capabilities = imap_session.capabilities().change_context(MyError::Imap)?;
+    let literals = Literals::from_capabilities(&capabilities);
+
+    // Only worth it with somewhere to keep what we've seen between runs
+    let qresync = account.envelope_cache_dir.is_some()
+        && capabilities.has_str("QRESYNC")
+        && my_enable(&mut imap_session, "QRESYNC").change_context(MyError::Imap)?;
+    if account.envelope_cache_dir.is_some() && !qresync {
+        debug!("Server doesn't do QRESYNC; searching each folder in full");
+    }
+
+    let mut ctx = Context {
+        imap_session: &mut imap_session,
+        noop: cli.dry_run,
+        gmail_delete_hack: account.gmail_delete_hack,
+        uid_chunk_size: account.uid_chunk_size,
+        clock,
+        summary: Summary::default(),
+        moved_mails: HashMap::new(),
+        notes: HashMap::new(),
+        labels: Vec::new(),
+        literals,
+        qresync,
+        folder: String::new(),
+        bodies: HashMap::new(),
+        envelope_cache: account
+            .envelope_cache_dir
+            .as_deref()
+            .map(|dir| EnvelopeCache::load(dir, &account.name)),
+    };
+
+    if matches!(cli.command, Command::Move | Command::Run) {
+        move_to_storage(&mut ctx, account)?;
+    }
+
+    if matches!(cli.command, Command::Check | Command::Run) {
+        for storage_folder_name in account.all_storage_folders() {
+            let matcher_sets = account
+                .handlers
+                .iter()
+                .filter(|x| account.handler_storage_folder(x) == storage_folder_name)
+                .collect::<Vec<_>>();
+
+            check_storage(&mut ctx, &matcher_sets, storage_folder_name)?;
+        }
     }
 
+    let summary = ctx.summary;
+
     // Be nice to the server and log out
     imap_session.logout().change_context(MyError::Imap)?;
 
-    Ok(())
+    Ok(summary)
 }
 
 #[tracing::instrument]
@@ -210,19 +486,373 @@ fn match_mail(name: &str, matchers: &Vec<Filter>, mail: &Mail) -> bool {
     return true;
 }
 
+// Splits the mails into the ones an IMAP search finds and the ones it doesn't; `what` is for the
+// error if the search fails.
+fn split_by_search<'m>(
+    ctx: &mut Context,
+    mails: &[&'m Mail],
+    search: &Search,
+    what: &str,
+) -> Result<(Vec<&'m Mail>, Vec<&'m Mail>), MyError> {
+    let uids: Vec<Uid> = mails.iter().map(|x| x.uid).collect();
+
+    // Get the list of all UIDs that match the search across the mails in question
+    let mut found_uids = std::collections::HashSet::new();
+    for chunk in chunks(what, &uids, ctx.uid_chunk_size) {
+        // Only look at the mails in question
+        let query = Search::And(vec![Search::Uids(chunk), search.clone()])
+            .to_query(ctx.literals)
+            .change_context(MyError::Imap)
+            .attach_printable_lazy(|| format!("Could not {what}"))?;
+        debug!("IMAP search string: {query}");
+
+        found_uids.extend(
+            my_uid_search(ctx.imap_session, query)
+                .change_context(MyError::Imap)
+                .attach_printable_lazy(|| format!("Could not {what}"))?,
+        );
+    }
+
+    Ok(mails.iter().partition(|x| found_uids.contains(&x.uid)))
+}
+
+// Splits the mails by whether their bodies contain any (or all) of the strings, by searching on the
+// server if it can take the strings, and by fetching the mails and looking through them here if
+// it can't (say, because they aren't ASCII and it doesn't do LITERAL+); servers mostly ignore case,
+// so that does too.
+fn split_by_server_search<'m>(
+    ctx: &mut Context,
+    mails: &[&'m Mail],
+    strings: &[String],
+    all: bool,
+    what: &str,
+) -> Result<(Vec<&'m Mail>, Vec<&'m Mail>), MyError> {
+    let search = if all {
+        Search::all_body(strings)
+    } else {
+        Search::any_body(strings)
+    };
+
+    if let Err(error) = search.to_query(ctx.literals) {
+        warn!("Can't {what} on the server, so searching the mails here instead: {error}");
+        return split_by_substrings(ctx, mails, strings, all, true);
+    }
+
+    split_by_search(ctx, mails, &search, what)
+}
+
+// Splits the mails into the ones whose bodies match the regex and the ones whose bodies don't,
+// which means fetching all of them.
+fn split_by_regex<'m>(
+    ctx: &mut Context,
+    mails: &[&'m Mail],
+    regex: &regex::Regex,
+) -> Result<(Vec<&'m Mail>, Vec<&'m Mail>), MyError> {
+    cache_whole_mails(ctx, mails)?;
+
+    let mut matched = Vec::new();
+    let mut not_matched = Vec::new();
+    for mail in mails {
+        if regex.is_match(body_text(ctx, mail)?) {
+            matched.push(*mail);
+        } else {
+            not_matched.push(*mail);
+        }
+    }
+
+    Ok((matched, not_matched))
+}
+
+// Splits the mails into the ones whose bodies contain any (or all) of the strings and the ones
+// whose bodies don't, by fetching them rather than searching on the server.  Like the server, this
+// looks through the decoded text, so quoted-printable and base64 don't get in the way.
+fn split_by_substrings<'m>(
+    ctx: &mut Context,
+    mails: &[&'m Mail],
+    strings: &[String],
+    all: bool,
+    ignore_case: bool,
+) -> Result<(Vec<&'m Mail>, Vec<&'m Mail>), MyError> {
+    cache_whole_mails(ctx, mails)?;
+
+    let mut matched = Vec::new();
+    let mut not_matched = Vec::new();
+    for mail in mails {
+        let found = match mailparse::parse_mail(whole_mail(ctx, mail)?) {
+            Ok(parsed) => contains_strings(&searchable_text(&parsed), strings, all, ignore_case),
+            Err(e) => {
+                warn!(
+                    "Couldn't parse mail from '{}' with subject '{}', so it doesn't contain any strings: {e}",
+                    mail.from_addr, mail.subject
+                );
+                false
+            }
+        };
+        if found {
+            matched.push(*mail);
+        } else {
+            not_matched.push(*mail);
+        }
+    }
+
+    Ok((matched, not_matched))
+}
+
+// Whether the text contains any (or all) of the strings.
+fn contains_strings(text: &str, strings: &[String], all: bool, ignore_case: bool) -> bool {
+    let (text, strings) = if ignore_case {
+        (
+            text.to_lowercase(),
+            strings.iter().map(|x| x.to_lowercase()).collect(),
+        )
+    } else {
+        (text.to_string(), strings.to_vec())
+    };
+
+    if all {
+        strings.iter().all(|x| text.contains(x.as_str()))
+    } else {
+        strings.iter().any(|x| text.contains(x.as_str()))
+    }
+}
+
+// Makes sure the whole of each of the given mails is in the body cache.
+fn cache_whole_mails(ctx: &mut Context, mails: &[&Mail]) -> Result<(), MyError> {
+    let uids: Vec<Uid> = mails
+        .iter()
+        .filter(|x| !ctx.bodies.contains_key(&(ctx.folder.clone(), x.uid)))
+        .map(|x| x.uid)
+        .collect();
+
+    if uids.is_empty() {
+        debug!("All {} mail bodies were already fetched", mails.len());
+        return Ok(());
+    }
+
+    for chunk in chunks("Fetching mail bodies", &uids, ctx.uid_chunk_size) {
+        let fetched = ctx
+            .imap_session
+            .uid_fetch(uid_set(&chunk), "BODY.PEEK[]")
+            .change_context(MyError::Imap)
+            .attach_printable("Couldn't fetch whole mails")?;
+
+        for mail in fetched.iter() {
+            if let (Some(uid), Some(body)) = (mail.uid, mail.body()) {
+                ctx.bodies
+                    .insert((ctx.folder.clone(), Uid::from(uid)), body.to_vec());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The UIDs split up for IMAP commands (see uid_chunks), logging progress as each chunk is used
+// when there's more than one.
+fn chunks<'a>(
+    what: &'a str,
+    uids: &[Uid],
+    chunk_size: usize,
+) -> impl Iterator<Item = Vec<Uid>> + 'a {
+    let chunks = uid_chunks(uids, chunk_size);
+    let count = chunks.len();
+    chunks.into_iter().enumerate().map(move |(index, chunk)| {
+        if count > 1 {
+            info!(
+                "{what}: chunk {} of {count} ({} mails)",
+                index + 1,
+                chunk.len()
+            );
+        }
+        chunk
+    })
+}
+
+// The whole of a mail from the body cache, which should already have it; if it doesn't, the
+// server didn't send it (say, because it had just been deleted).
+fn whole_mail<'c>(ctx: &'c Context, mail: &Mail) -> Result<&'c [u8], MyError> {
+    ctx.bodies
+        .get(&(ctx.folder.clone(), mail.uid))
+        .map(Vec::as_slice)
+        .ok_or_else(|| {
+            Report::new(MyError::MailFormat("Mail has no body")).attach_printable(format!(
+                "from_addr: {}, subject: {}, date: {}",
+                mail.from_addr, mail.subject, mail.date
+            ))
+        })
+}
+
+// The undecoded body of a mail from the body cache, i.e. everything after the headers, which is
+// what BodyCheckRegex has always matched against.
+fn body_text<'c>(ctx: &'c Context, mail: &Mail) -> Result<&'c str, MyError> {
+    let whole = whole_mail(ctx, mail)?;
+    let header_length = mailparse::parse_headers(whole).map_or(0, |x| x.1);
+
+    std::str::from_utf8(&whole[header_length..])
+        .change_context(MyError::MailFormat("Mail body was not valid utf-8"))
+        .attach_printable_lazy(|| {
+            format!(
+                "from_addr: {}, subject: {}, date: {}",
+                mail.from_addr, mail.subject, mail.date
+            )
+        })
+}
+
+// The decoded text of a mail: its first text/plain part, or its whole body if it hasn't got one.
+fn text_body(parsed: &mailparse::ParsedMail) -> Option<String> {
+    fn find_plain<'a, 'b>(
+        part: &'b mailparse::ParsedMail<'a>,
+    ) -> Option<&'b mailparse::ParsedMail<'a>> {
+        if part.subparts.is_empty() {
+            (part.ctype.mimetype == "text/plain").then_some(part)
+        } else {
+            part.subparts.iter().find_map(find_plain)
+        }
+    }
+
+    find_plain(parsed).unwrap_or(parsed).get_body().ok()
+}
+
+// The decoded text of every text part of a mail, which is what a server's SEARCH BODY looks
+// through.  Bytes that aren't valid in a part's charset come out as replacement characters.
+fn searchable_text(parsed: &mailparse::ParsedMail) -> String {
+    if parsed.subparts.is_empty() {
+        if parsed.ctype.mimetype.starts_with("text/") {
+            parsed.get_body().unwrap_or_default()
+        } else {
+            String::new()
+        }
+    } else {
+        parsed
+            .subparts
+            .iter()
+            .map(searchable_text)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+// The given part of each mail, in the same order as the mails; None for mails that don't have it.
+fn capture_texts(
+    ctx: &mut Context,
+    field: &CaptureField,
+    mails: &[&Mail],
+) -> Result<Vec<Option<String>>, MyError> {
+    Ok(match field {
+        CaptureField::Subject => mails.iter().map(|x| Some(x.subject.clone())).collect(),
+        CaptureField::From => mails.iter().map(|x| Some(x.from_addr.clone())).collect(),
+        CaptureField::Body | CaptureField::Header(_) => {
+            cache_whole_mails(ctx, mails)?;
+            let mut texts = Vec::new();
+            for mail in mails {
+                let parsed = match mailparse::parse_mail(whole_mail(ctx, mail)?) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!(
+                            "Couldn't parse mail from '{}' with subject '{}': {e}",
+                            mail.from_addr, mail.subject
+                        );
+                        texts.push(None);
+                        continue;
+                    }
+                };
+                texts.push(match field {
+                    CaptureField::Header(header) => parsed.headers.get_first_value(header),
+                    _ => text_body(&parsed),
+                });
+            }
+            texts
+        }
+    })
+}
+
+// The number the capture finds in each mail, in the same order as the mails; None for mails it
+// doesn't find one in.  The numbers are noted for any Alert further down.
+fn capture_numbers(
+    ctx: &mut Context,
+    capture: &Capture,
+    mails: &[&Mail],
+) -> Result<Vec<Option<f64>>, MyError> {
+    let texts = capture_texts(ctx, &capture.field, mails)?;
+    Ok(mails
+        .iter()
+        .zip(texts)
+        .map(|(mail, text)| {
+            let found = text.as_deref().and_then(|x| capture.find(x));
+            let Some(value) = found.and_then(|x| x.trim().parse::<f64>().ok()) else {
+                trace!(
+                    "Mail uid {}, subject: {}: no number found for {}; found {found:?}",
+                    mail.uid,
+                    mail.subject,
+                    capture.name
+                );
+                return None;
+            };
+
+            trace!(
+                "Mail uid {}, subject: {}: {} is {value}",
+                mail.uid,
+                mail.subject,
+                capture.name
+            );
+            ctx.notes
+                .entry(mail.uid)
+                .or_default()
+                .push(format!("{} = {value}", capture.name));
+            Some(value)
+        })
+        .collect())
+}
+
+// Splits the mails into the ones the selection picks out and the rest.
+fn select<'m>(
+    ctx: &mut Context,
+    name: &str,
+    selection: &Selection,
+    mails: &[&'m Mail],
+) -> Result<(Vec<&'m Mail>, Vec<&'m Mail>), MyError> {
+    if mails.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+
+    Ok(match selection {
+        Selection::Matchers(matchers) => mails.iter().partition(|x| match_mail(name, matchers, x)),
+        // Nothing contains any of no strings, and everything contains all of them
+        Selection::BodyAny(strings) if strings.is_empty() => (Vec::new(), mails.to_vec()),
+        Selection::BodyAll(strings) if strings.is_empty() => (mails.to_vec(), Vec::new()),
+        Selection::BodyAny(strings) => split_by_server_search(
+            ctx,
+            mails,
+            strings,
+            false,
+            &format!("select mail bodies with any of the strings {strings:?}"),
+        )?,
+        Selection::BodyAll(strings) => split_by_server_search(
+            ctx,
+            mails,
+            strings,
+            true,
+            &format!("select mail bodies with all of the strings {strings:?}"),
+        )?,
+        Selection::BodyRegex(regex) => split_by_regex(ctx, mails, regex)?,
+    })
+}
+
 #[tracing::instrument(skip(mail))]
-fn get_match_data<'a>(
-    mail: &'a imap::types::Fetch,
-) -> Option<(Uid, String, String, time::OffsetDateTime)> {
-    let envelope = mail.envelope().expect("Mail did not have an envelope!");
-    let uid: Uid = mail.uid.expect("Mail has no UID").into();
-
-    //let mail_id = match envelope.mail_id {
-    //    Some(ref x) => std::str::from_utf8(x)
-    //        .expect("Mail ID was not valid utf-8")
-    //        .to_string(),
-    //    None => format!("NO MAIL ID FOUND for uid {}", mail.uid).to_owned(),
-    //};
+fn get_match_data<'a>(mail: &'a imap::types::Fetch) -> Option<Mail> {
+    let (Some(envelope), Some(uid)) = (mail.envelope(), mail.uid) else {
+        warn!("Server didn't give the envelope or UID of a mail, skipping: {mail:?}");
+        return None;
+    };
+    let uid = Uid::from(uid);
+
+    // Only used for spotting duplicates, so a missing or broken one isn't worth skipping the
+    // mail over
+    let message_id = envelope
+        .message_id
+        .as_ref()
+        .and_then(|x| std::str::from_utf8(x).ok())
+        .map(str::to_string);
 
     let from_addr_temp = addresses_to_string(envelope.from.as_ref());
 
@@ -243,8 +873,32 @@ fn get_match_data<'a>(
         Err(x) => Err(x),
     };
 
+    let internal_date = mail.internal_date().and_then(|x| {
+        let offset = time::UtcOffset::from_whole_seconds(x.offset().local_minus_utc()).ok()?;
+        time::OffsetDateTime::from_unix_timestamp(x.timestamp())
+            .ok()
+            .map(|x| x.to_offset(offset))
+    });
+
+    if mail.size.is_none() || mail.bodystructure().is_none() {
+        warn!("Server didn't give the size or structure of the mail with uid {uid}");
+    }
+    let mut parts = Vec::new();
+    if let Some(structure) = mail.bodystructure() {
+        mail_parts(structure, &mut parts);
+    }
+
     if let (Ok(from_addr), Ok(subject), Ok(date)) = (&from_addr_temp, &subject_temp, &date_temp) {
-        Some((uid, from_addr.clone(), (*subject).to_string(), *date))
+        Some(Mail {
+            uid,
+            subject: (*subject).to_string(),
+            from_addr: from_addr.clone(),
+            date: *date,
+            internal_date,
+            message_id,
+            size: mail.size,
+            parts,
+        })
     } else {
         let from_addr_str = match from_addr_temp {
             Err(x) => x.to_string(),
@@ -263,10 +917,48 @@ fn get_match_data<'a>(
     }
 }
 
+// Flattens a BODYSTRUCTURE into its leaf parts.  A forwarded mail is one part, rather than
+// whatever's inside it.
+fn mail_parts(structure: &imap_proto::types::BodyStructure, parts: &mut Vec<MailPart>) {
+    use imap_proto::types::BodyStructure;
+
+    let common = match structure {
+        BodyStructure::Multipart { bodies, .. } => {
+            for body in bodies {
+                mail_parts(body, parts);
+            }
+            return;
+        }
+        BodyStructure::Basic { common, .. }
+        | BodyStructure::Text { common, .. }
+        | BodyStructure::Message { common, .. } => common,
+    };
+
+    let param = |params: &imap_proto::types::BodyParams, name: &str| {
+        params.as_ref().and_then(|x| {
+            x.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.to_string())
+        })
+    };
+
+    let disposition = common.disposition.as_ref();
+    let filename = disposition
+        .and_then(|x| param(&x.params, "filename"))
+        .or_else(|| param(&common.ty.params, "name"));
+    parts.push(MailPart {
+        mime_type: format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase(),
+        attachment: filename.is_some()
+            || disposition.is_some_and(|x| x.ty.eq_ignore_ascii_case("attachment")),
+        filename,
+    });
+}
+
 fn get_mails(
     imap_session: &mut imap::Session<Box<dyn imap::ImapConnection>>,
-    uids: &Vec<Uid>,
-) -> Vec<Mail> {
+    uids: &[Uid],
+    uid_chunk_size: usize,
+) -> Result<Vec<Mail>, MyError> {
     debug!("IMAP search results: {uids:?}");
 
     info!(
@@ -276,72 +968,68 @@ fn get_mails(
 
     let mut mails = Vec::new();
 
-    let uids_list = uids
-        .iter()
-        .map(std::string::ToString::to_string)
-        .collect::<Vec<_>>()
-        .join(",");
-
-    let raw_mails = imap_session
-        .uid_fetch(uids_list, "(ENVELOPE)")
-        .expect("Couldn't fetch mails!");
-
-    for mail in raw_mails.iter() {
-        if let Some((uid, from_addr, subject, date)) = get_match_data(mail) {
-            mails.push(Mail {
-                uid,
-                subject,
-                from_addr,
-                date,
-            });
+    for chunk in chunks("Fetching mails", uids, uid_chunk_size) {
+        let raw_mails = imap_session
+            .uid_fetch(
+                uid_set(&chunk),
+                "(ENVELOPE INTERNALDATE RFC822.SIZE BODYSTRUCTURE)",
+            )
+            .change_context(MyError::Imap)
+            .attach_printable("Couldn't fetch mails")?;
+
+        for mail in raw_mails.iter() {
+            if let Some(mail) = get_match_data(mail) {
+                mails.push(mail);
+            }
         }
     }
 
     info!("Done fetching and processing {} mails", uids.len());
 
-    mails
+    Ok(mails)
 }
 
-#[tracing::instrument(skip(matcher_sets,imap_session), fields(matcher_sets_count = matcher_sets.len()))]
-fn move_to_storage(
-    imap_session: &mut imap::Session<Box<dyn imap::ImapConnection>>,
-    matcher_sets: Vec<Handler>,
-    inbox_name: &str,
-    storage_folder_name: &str,
-    days_back: i64,
-    noop: bool,
-) -> Result<(), MyError> {
-    imap_session
-        .select(inbox_name)
-        .change_context(MyError::Imap)?;
-
-    #[allow(clippy::needless_late_init)]
-    let odt_formatted: String;
+#[tracing::instrument(skip_all)]
+fn move_to_storage(ctx: &mut Context, account: &Account) -> Result<(), MyError> {
+    for source_folder in account.all_source_folders() {
+        let matcher_sets = account
+            .handlers
+            .iter()
+            .filter(|x| account.handler_watches(x, source_folder))
+            .collect::<Vec<_>>();
 
-    if get_environment() == amcheck::configuration::Environment::Test {
-        // For testing, just statically go back far enough to cover everything that's in there
-        odt_formatted = "SINCE 01-Jan-2000".to_string();
-    } else {
-        // Generate a date like "SINCE 02-Sep-2023" that goes back 2 months-ish
-        let date_format = time::format_description::parse("SINCE [day]-[month repr:short]-[year]")
-            .change_context(MyError::DateFormatting)?;
-        let odt = time::OffsetDateTime::now_local()
-            .change_context(MyError::DateFormatting)?
-            .checked_sub(time::Duration::days(days_back))
-            .ok_or(MyError::DateSubtraction(days_back))?;
-        odt_formatted = odt
-            .format(&date_format)
-            .change_context(MyError::DateFormatting)?;
+        move_folder_to_storage(ctx, account, &matcher_sets, source_folder)?;
     }
 
-    debug!("IMAP search string: {odt_formatted}");
+    Ok(())
+}
+
+#[tracing::instrument(skip(ctx, account, matcher_sets), fields(matcher_sets_count = matcher_sets.len()))]
+fn move_folder_to_storage(
+    ctx: &mut Context,
+    account: &Account,
+    matcher_sets: &[&Handler],
+    source_folder: &str,
+) -> Result<(), MyError> {
+    let days_back = account.days_back;
 
-    let uids =
-        my_uid_search(imap_session, odt_formatted).expect("Could not search for recent mails!");
+    let mailbox = ctx
+        .imap_session
+        .select(source_folder)
+        .change_context(MyError::Imap)?;
+    ctx.folder = source_folder.to_string();
+
+    // Go back 2 months-ish
+    let odt = ctx
+        .clock
+        .now()
+        .checked_sub(time::Duration::days(days_back))
+        .ok_or(MyError::DateSubtraction(days_back))?;
 
-    let mails = get_mails(imap_session, &Vec::from_iter(uids));
+    let mails = folder_mails(ctx, source_folder, &mailbox, Some(odt.date()))?;
 
-    let mut storables = Vec::new();
+    // Mails to move, grouped by the storage folder they're going to
+    let mut storables: Vec<(&str, Vec<Mail>)> = Vec::new();
 
     for mail in mails {
         trace!(
@@ -351,7 +1039,7 @@ fn move_to_storage(
             mail.subject
         );
 
-        for matcher_set in &matcher_sets {
+        for matcher_set in matcher_sets {
             // Special case: empty matcher sets are ignored during the move phase, but treated as
             // matching everything during the check phase
             if !matcher_set.filters.is_empty()
@@ -361,7 +1049,14 @@ fn move_to_storage(
                     "Marking mail to move to storage from set {}: From {}, subj {}",
                     matcher_set.name, mail.from_addr, mail.subject
                 );
-                storables.push(mail.uid.to_string());
+                let storage_folder_name = account.handler_storage_folder(matcher_set);
+                match storables
+                    .iter_mut()
+                    .find(|(x, _)| *x == storage_folder_name)
+                {
+                    Some((_, folder_mails)) => folder_mails.push(mail),
+                    None => storables.push((storage_folder_name, vec![mail])),
+                }
                 break;
             }
         }
@@ -369,53 +1064,206 @@ fn move_to_storage(
 
     if storables.is_empty() {
         info!("No mails to move.");
-    } else if noop {
+    }
+
+    for (storage_folder_name, folder_mails) in storables {
+        store_mails(ctx, storage_folder_name, folder_mails)?;
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(ctx, mails))]
+fn store_mails(
+    ctx: &mut Context,
+    storage_folder_name: &str,
+    mails: Vec<Mail>,
+) -> Result<(), MyError> {
+    if ctx.noop {
         info!(
             "In no-op mode, not moving {} mails to storage.",
-            storables.len()
+            mails.len()
         );
-    } else {
-        // Check if the storage folder exists
-        let names = imap_session
-            .list(Some(""), Some(storage_folder_name))
+        return Ok(());
+    }
+
+    if !folder_exists(ctx, storage_folder_name)? {
+        info!("{storage_folder_name} doesn't exist, creating");
+        ctx.imap_session
+            .create(storage_folder_name)
             .change_context(MyError::Imap)?;
+    }
+
+    let uids: Vec<Uid> = mails.iter().map(|x| x.uid).collect();
+
+    info!("Moving {} mails to storage.", mails.len());
+    let mut new_uids = HashMap::new();
+    for chunk in chunks("Moving mails", &uids, ctx.uid_chunk_size) {
+        new_uids.extend(
+            my_uid_mv(ctx.imap_session, uid_set(&chunk), storage_folder_name)
+                .change_context(MyError::Imap)?,
+        );
+    }
+    ctx.summary.moved += mails.len();
 
-        if names.is_empty() {
-            info!("{storage_folder_name} doesn't exist, creating");
-            imap_session
-                .create(storage_folder_name)
-                .change_context(MyError::Imap)?;
+    // Hang on to the mails we know the new UIDs of, in case we're checking storage next
+    let moved_mails = ctx
+        .moved_mails
+        .entry(storage_folder_name.to_string())
+        .or_default();
+    for mail in mails {
+        if let Some(new_uid) = new_uids.get(&mail.uid) {
+            moved_mails.push(Mail {
+                uid: *new_uid,
+                ..mail
+            });
         }
+    }
+
+    Ok(())
+}
+
+fn folder_exists(ctx: &mut Context, folder_name: &str) -> Result<bool, MyError> {
+    let names = ctx
+        .imap_session
+        .list(Some(""), Some(folder_name))
+        .change_context(MyError::Imap)?;
 
-        info!("Moving {} mails to storage.", storables.len());
-        // imap.mv returns no information at all except failure
-        imap_session
-            .uid_mv(storables.join(","), storage_folder_name)
+    Ok(!names.is_empty())
+}
+
+// Every mail in the selected folder that the server got on or after `since`, or all of them.
+// With an envelope cache, only the mails that aren't in it get fetched, and with QRESYNC as well,
+// rather than searching the whole folder we just ask the server what's come and gone since last
+// time.
+fn folder_mails(
+    ctx: &mut Context,
+    folder: &str,
+    mailbox: &imap::types::Mailbox,
+    since: Option<time::Date>,
+) -> Result<Vec<Mail>, MyError> {
+    // Anything the move phase just put here we've already parsed
+    let mut moved_mails: HashMap<Uid, Mail> = ctx
+        .moved_mails
+        .remove(folder)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| (x.uid, x))
+        .collect();
+
+    // And anything that was here last time, if the UIDs still mean the same mails
+    let (mut cached_mails, cached_mod_seq) = match (&mut ctx.envelope_cache, mailbox.uid_validity) {
+        (Some(cache), Some(uid_validity)) => cache.take(folder, uid_validity),
+        _ => (HashMap::new(), None),
+    };
+
+    let uids: Vec<Uid> = if let Some(mod_seq) = cached_mod_seq.filter(|_| ctx.qresync) {
+        let (changed, vanished) =
+            my_uid_changed_since(ctx.imap_session, mod_seq).change_context(MyError::Imap)?;
+        debug!(
+            "Since the last run, {} mails in {folder} have appeared or changed and {} have gone",
+            changed.len(),
+            vanished.len()
+        );
+        cached_mails
+            .keys()
+            .copied()
+            .filter(|x| !vanished.contains(x))
+            .chain(changed)
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    } else {
+        let search = since.map_or(Search::All, Search::Since);
+        let query = search
+            .to_query(ctx.literals)
             .change_context(MyError::Imap)?;
+        debug!("IMAP search string: {query}");
+        my_uid_search(ctx.imap_session, query)
+            .change_context(MyError::Imap)
+            .attach_printable("Could not search for mails")?
+            .into_iter()
+            .collect()
+    };
+
+    let mut mails = Vec::new();
+    let mut unknown_uids = Vec::new();
+    let mut cached_count = 0;
+    for uid in uids {
+        if let Some(mail) = moved_mails.remove(&uid) {
+            mails.push(mail);
+        } else if let Some(mail) = cached_mails.remove(&uid) {
+            mails.push(mail);
+            cached_count += 1;
+        } else {
+            unknown_uids.push(uid);
+        }
     }
 
-    Ok(())
+    if !mails.is_empty() {
+        debug!(
+            "Reusing {} mails from the move phase and {cached_count} from the envelope cache; fetching the other {}.",
+            mails.len() - cached_count,
+            unknown_uids.len()
+        );
+    }
+
+    mails.extend(get_mails(
+        ctx.imap_session,
+        &unknown_uids,
+        ctx.uid_chunk_size,
+    )?);
+    mails.sort_by_key(|x| x.uid);
+
+    // Without the search, old mails that are still in the folder come back too; dropping them
+    // before they're cached keeps the cache to the same window.  This goes by when the server got
+    // them, as SINCE does, so that both ways pick the same mails.
+    if let Some(since) = since {
+        mails.retain(|x| x.internal_date.unwrap_or(x.date).date() >= since);
+    }
+
+    // UIDs that weren't found above have gone from the folder, so this drops them
+    if let (Some(cache), Some(uid_validity)) = (&mut ctx.envelope_cache, mailbox.uid_validity) {
+        cache.put(
+            folder,
+            uid_validity,
+            mailbox.highest_mod_seq,
+            mails.iter().map(|x| (x.uid, x.clone())).collect(),
+        );
+        // A dry run shouldn't leave anything behind, even a cache
+        if ctx.noop {
+            debug!("In no-op mode, not saving the envelope cache");
+        } else if let Err(error) = cache.save() {
+            warn!("Couldn't save the envelope cache: {error}");
+        }
+    }
+
+    Ok(mails)
 }
 
-#[tracing::instrument(skip(matcher_sets, imap_session))]
+#[tracing::instrument(skip(ctx, matcher_sets))]
 fn check_storage(
-    imap_session: &mut imap::Session<Box<dyn imap::ImapConnection>>,
-    matcher_sets: Vec<Handler>,
+    ctx: &mut Context,
+    matcher_sets: &[&Handler],
     storage_folder_name: &str,
-    noop: bool,
-    gmail_delete_hack: bool,
 ) -> Result<(), MyError> {
-    imap_session
-        .select(storage_folder_name)
-        .change_context(MyError::Imap)?;
-
-    debug!("Pulling list of IMAP UIDs.");
-    let uids = my_uid_search(imap_session, "ALL").expect("Could not search for recent mails!");
+    let mails = if folder_exists(ctx, storage_folder_name)? {
+        let mailbox = ctx
+            .imap_session
+            .select(storage_folder_name)
+            .change_context(MyError::Imap)?;
+        ctx.folder = storage_folder_name.to_string();
 
-    let mails = get_mails(imap_session, &Vec::from_iter(uids));
+        folder_mails(ctx, storage_folder_name, &mailbox, None)?
+    } else {
+        // Nothing has ever been moved there, but the checks still need to run so that counts
+        // of zero get noticed
+        info!("{storage_folder_name} doesn't exist, so it has no mails to check");
+        Vec::new()
+    };
 
     // Walk through the list of checks
-    for matcher_set in &matcher_sets {
+    for matcher_set in matcher_sets {
         if matches!(matcher_set.checker_tree, CheckerTree::Stop) {
             continue;
         }
@@ -446,13 +1294,12 @@ fn check_storage(
             println!("\n\n-------------------------------------\n\n");
         }
 
+        ctx.notes.clear();
         run_check_tree(
-            noop,
+            ctx,
             &matcher_set.name,
             &matcher_set.checker_tree,
-            imap_session,
             &checkables,
-            gmail_delete_hack,
         )?;
     }
 
@@ -464,22 +1311,36 @@ fn print_head_of(tree: &CheckerTree) -> String {
         CheckerTree::Stop => "Stop".to_string(),
         CheckerTree::Action(x) => format!("Action {x:?}"),
         CheckerTree::MatchCheck(x) => format!("MatchCheck {:?}", x.matchers),
-        CheckerTree::DateCheck(x) => format!("DateCheck {}", x.days),
+        CheckerTree::DateCheck(x) => format!("DateCheck {}", x.cutoff),
         CheckerTree::CountCheck(x) => format!("CountCheck {}", x.count),
+        CheckerTree::CountRangeCheck(x) => format!("CountRangeCheck {} to {:?}", x.min, x.max),
+        CheckerTree::RatioCheck(x) => format!("RatioCheck {:?}", x.numerator),
+        CheckerTree::ExtractCheck(x) => format!("ExtractCheck {} {}", x.capture, x.comparison),
+        CheckerTree::GroupBy(x) => format!("GroupBy {}", x.key),
+        CheckerTree::Correlate(x) => format!("Correlate {}", x.key),
+        CheckerTree::Latest(x) => format!("Latest {}", x.n),
+        CheckerTree::DuplicateCheck(x) => format!("DuplicateCheck {:?}", x.by),
+        CheckerTree::SizeCheck(x) => format!("SizeCheck {} to {:?}", x.min_bytes, x.max_bytes),
+        CheckerTree::AttachmentCheck(x) => {
+            format!("AttachmentCheck {:?} {:?}", x.filename, x.mime_type)
+        }
+        CheckerTree::AggregateCheck(x) => format!(
+            "AggregateCheck {:?} of {} {}",
+            x.aggregate, x.capture, x.comparison
+        ),
         CheckerTree::BodyCheckAny(x) => format!("BodyCheckAny {:?}", x.strings),
         CheckerTree::BodyCheckAll(x) => format!("BodyCheckAny {:?}", x.strings),
         CheckerTree::BodyCheckRegex(x) => format!("BodyCheckAny {:?}", x.regex),
+        CheckerTree::ExpectSchedule(x) => format!("ExpectSchedule {}", x.cron),
     }
 }
 
-#[tracing::instrument(skip(checker_tree, mails, imap_session), fields(tree_head_type = print_head_of(checker_tree)), level="error")]
+#[tracing::instrument(skip(ctx, checker_tree, mails), fields(tree_head_type = print_head_of(checker_tree)), level="error")]
 fn run_check_tree(
-    noop: bool,
+    ctx: &mut Context,
     name: &str,
     checker_tree: &CheckerTree,
-    imap_session: &mut imap::Session<Box<dyn imap::ImapConnection>>,
     mails: &Vec<&Mail>,
-    gmail_delete_hack: bool,
 ) -> Result<(), MyError> {
     // NOTE: Do *not* wrap this in a mails.is_empty(), because we want to fail counts that have 0
     // matches
@@ -493,26 +1354,35 @@ fn run_check_tree(
                 mails.len()
             );
 
+            let labels = if ctx.labels.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", ctx.labels.join(", "))
+            };
+
             match action {
                 Action::Alert => {
-                    warn!("CHECK FAILED for check '{name}' for {} mails; details for first 10 (or fewer) mails follows", mails.len());
+                    ctx.summary.failed.push(name.to_string());
+                    warn!("CHECK FAILED for check '{name}'{labels} for {} mails; details for first 10 (or fewer) mails follows", mails.len());
                     for mail in mails.iter().take(9) {
-                        warn!("CHECK FAILED DETAILS for check '{name}': mail from '{}' with subject '{}' and date '{}'!", mail.from_addr, mail.subject, mail.date);
+                        let notes = ctx
+                            .notes
+                            .get(&mail.uid)
+                            .map(|x| format!(" ({})", x.join(", ")))
+                            .unwrap_or_default();
+                        warn!("CHECK FAILED DETAILS for check '{name}': mail from '{}' with subject '{}' and date '{}'{notes}!", mail.from_addr, mail.subject, mail.date);
                     }
                 }
                 Action::Nothing => {}
                 Action::Success => {
-                    info!("Check '{name}' passed with {} mails", mails.len());
+                    ctx.summary.passed += 1;
+                    info!("Check '{name}'{labels} passed with {} mails", mails.len());
                 }
                 Action::Delete => {
                     if !mails.is_empty() {
-                        let uids_list = mails
-                            .iter()
-                            .map(|x| x.uid.to_string())
-                            .collect::<Vec<_>>()
-                            .join(",");
+                        let uids: Vec<Uid> = mails.iter().map(|x| x.uid).collect();
 
-                        if noop {
+                        if ctx.noop {
                             info!(
                                 "In noop mode, not deleting {} mails for check '{name}'",
                                 mails.len()
@@ -520,22 +1390,27 @@ fn run_check_tree(
                         } else {
                             info!("Deleting {} mails for check '{name}'", mails.len());
 
-                            if gmail_delete_hack {
+                            if ctx.gmail_delete_hack {
                                 // As of Dec 2024, (1) deleting things in gmail doesn't actually
                                 // delete them, even if you have your imap settings correct and (2)
                                 // move to trash crashes (see
                                 // https://github.com/d99kris/nmail/issues/172 ) ; this works
                                 // around both issues.
-                                imap_session
-                                    .uid_copy(&uids_list, "[Gmail]/Trash")
-                                    .change_context(MyError::Imap)?;
-                                imap_session.expunge().change_context(MyError::Imap)?;
+                                for chunk in chunks("Copying to trash", &uids, ctx.uid_chunk_size) {
+                                    ctx.imap_session
+                                        .uid_copy(uid_set(&chunk), "[Gmail]/Trash")
+                                        .change_context(MyError::Imap)?;
+                                }
+                                ctx.imap_session.expunge().change_context(MyError::Imap)?;
                             }
 
-                            imap_session
-                                .uid_store(&uids_list, "+FLAGS (\\Deleted)")
-                                .change_context(MyError::Imap)?;
-                            imap_session.expunge().change_context(MyError::Imap)?;
+                            for chunk in chunks("Deleting", &uids, ctx.uid_chunk_size) {
+                                ctx.imap_session
+                                    .uid_store(uid_set(&chunk), "+FLAGS (\\Deleted)")
+                                    .change_context(MyError::Imap)?;
+                            }
+                            ctx.imap_session.expunge().change_context(MyError::Imap)?;
+                            ctx.summary.deleted += mails.len();
                         }
                     }
                 }
@@ -571,44 +1446,30 @@ fn run_check_tree(
 
             // Dispatch the two lists down the tree
             if !matched.is_empty() || check.empty_ok == MatchEmpty::Matched {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.matched,
-                    imap_session,
-                    &matched,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.matched, &matched)?;
             }
             if !not_matched.is_empty() || check.empty_ok == MatchEmpty::NotMatched {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.not_matched,
-                    imap_session,
-                    &not_matched,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.not_matched, &not_matched)?;
             }
         }
         CheckerTree::DateCheck(check) => {
             debug!(
-                "Start of DateCheck for days ago {}, checking {} mails",
-                check.days,
+                "Start of DateCheck for cutoff {}, checking {} mails",
+                check.cutoff,
                 mails.len()
             );
 
-            // Build a list of mails with dates before and after the given number of days ago
+            // Build a list of mails with dates before and after the cutoff
             let mut older = Vec::new();
             let mut younger = Vec::new();
 
             if !mails.is_empty() {
-                for mail in mails {
-                    let odt = time::OffsetDateTime::now_local()
-                        .change_context(MyError::DateFormatting)?
-                        .checked_sub(time::Duration::days(i64::from(check.days)))
-                        .ok_or(MyError::DateSubtraction(i64::from(check.days)))?;
+                let odt = check
+                    .cutoff
+                    .before(ctx.clock)
+                    .ok_or_else(|| MyError::DateCutoff(check.cutoff.to_string()))?;
 
+                for mail in mails {
                     trace!("Checking if mail date {:?} for uid {}: from_addr: {}, subject: {} is older/younger than target date {odt:?}", mail.date, mail.uid, mail.from_addr, mail.subject);
 
                     // An older mail has a smaller date, so smaller mail_date is true
@@ -621,32 +1482,18 @@ fn run_check_tree(
                 }
 
                 debug!(
-                    "End of DateCheck for days ago {}; {} mails matched and {} mails not_matched",
-                    check.days,
+                    "End of DateCheck for cutoff {}; {} mails matched and {} mails not_matched",
+                    check.cutoff,
                     older.len(),
                     younger.len()
                 );
             }
 
             if !older.is_empty() || check.empty_ok == DateEmpty::OlderThan {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.older_than,
-                    imap_session,
-                    &older,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.older_than, &older)?;
             }
             if !younger.is_empty() || check.empty_ok == DateEmpty::YoungerThan {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.younger_than,
-                    imap_session,
-                    &younger,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.younger_than, &younger)?;
             }
         }
         CheckerTree::BodyCheckAny(check) => {
@@ -660,48 +1507,30 @@ fn run_check_tree(
             );
 
             if !mails.is_empty() {
-                let uids: Vec<Uid> = mails.iter().map(|x| x.uid).collect();
-                let uids_list = uids
-                    .iter()
-                    .map(std::string::ToString::to_string)
-                    .collect::<Vec<_>>()
-                    .join(",");
-
-                let search_string: String;
-
                 if check.strings.is_empty() {
                     warn!("List of strings for BodyCheckAny check '{name}' is empty; terminating tree here.");
                     return Ok(());
-                } else if check.strings.len() == 1 {
-                    search_string = format!("BODY \"{}\"", check.strings[0]);
-                } else {
-                    search_string = check.strings[1..]
-                        .iter()
-                        .fold(format!("BODY \"{}\"", check.strings[0]), |acc, x| {
-                            format!("OR (BODY \"{x}\") ({acc})")
-                        });
-                }
-
-                debug!("In BodyCheckAny '{name}', search string is {search_string}.");
-
-                // Get the list of all UIDs that match the body text in question across the mails in
-                // question
-                let body_text_uids =
-                    my_uid_search(imap_session, format!("UID {uids_list} {search_string}"))
-                        .unwrap_or_else(|x| {
-                            panic!(
-                    "Could not BodyCheckAny for mail bodies with strings {:?}, error: {x:?}!",
-                    check.strings,
-                )
-                        });
-
-                for mail in mails {
-                    if body_text_uids.contains(&mail.uid) {
-                        matched.push(*mail);
-                    } else {
-                        not_matched.push(*mail);
-                    }
                 }
+
+                (matched, not_matched) = match check.search {
+                    BodySearch::Server => split_by_server_search(
+                        ctx,
+                        mails,
+                        &check.strings,
+                        false,
+                        &format!(
+                            "BodyCheckAny for mail bodies with strings {:?}",
+                            check.strings
+                        ),
+                    ),
+                    BodySearch::Substring | BodySearch::SubstringIgnoreCase => split_by_substrings(
+                        ctx,
+                        mails,
+                        &check.strings,
+                        false,
+                        check.search == BodySearch::SubstringIgnoreCase,
+                    ),
+                }?;
             }
 
             debug!(
@@ -712,24 +1541,10 @@ fn run_check_tree(
             );
 
             if !matched.is_empty() || check.empty_ok == MatchEmpty::Matched {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.matched,
-                    imap_session,
-                    &matched,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.matched, &matched)?;
             }
             if !not_matched.is_empty() || check.empty_ok == MatchEmpty::NotMatched {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.not_matched,
-                    imap_session,
-                    &not_matched,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.not_matched, &not_matched)?;
             }
         }
         CheckerTree::BodyCheckAll(check) => {
@@ -743,51 +1558,30 @@ fn run_check_tree(
             let mut not_matched = Vec::new();
 
             if !mails.is_empty() {
-                let uids: Vec<Uid> = mails.iter().map(|x| x.uid).collect();
-                let uids_list = uids
-                    .iter()
-                    .map(std::string::ToString::to_string)
-                    .collect::<Vec<_>>()
-                    .join(",");
-
-                let search_string: String;
-
                 if check.strings.is_empty() {
                     warn!("List of strings for BodyCHeckAll check '{name}' is empty; terminating tree here.");
                     return Ok(());
-                } else if check.strings.len() == 1 {
-                    search_string = format!("BODY \"{}\"", check.strings[0]);
-                } else {
-                    search_string = check.strings[1..]
-                        .iter()
-                        .fold(format!("BODY \"{}\"", check.strings[0]), |acc, x| {
-                            format!("BODY \"{x}\" {acc}")
-                        });
                 }
 
-                let final_search = format!("UID {uids_list} {search_string}");
-                debug!(
-                    "In BodyCheckAll '{name}', search string is: {}",
-                    final_search
-                );
-
-                // Get the list of all UIDs that match the body text in question across the mails in
-                // question
-                let body_text_uids =
-                    my_uid_search(imap_session, final_search).unwrap_or_else(|x| {
-                        panic!(
-                    "Could not BodyCheckAll for mail bodies with strings {:?}, error: {x:?}!",
-                    check.strings,
-                )
-                    });
-
-                for mail in mails {
-                    if body_text_uids.contains(&mail.uid) {
-                        matched.push(*mail);
-                    } else {
-                        not_matched.push(*mail);
-                    }
-                }
+                (matched, not_matched) = match check.search {
+                    BodySearch::Server => split_by_server_search(
+                        ctx,
+                        mails,
+                        &check.strings,
+                        true,
+                        &format!(
+                            "BodyCheckAll for mail bodies with strings {:?}",
+                            check.strings
+                        ),
+                    ),
+                    BodySearch::Substring | BodySearch::SubstringIgnoreCase => split_by_substrings(
+                        ctx,
+                        mails,
+                        &check.strings,
+                        true,
+                        check.search == BodySearch::SubstringIgnoreCase,
+                    ),
+                }?;
             }
 
             debug!(
@@ -798,24 +1592,10 @@ fn run_check_tree(
             );
 
             if !matched.is_empty() || check.empty_ok == MatchEmpty::Matched {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.matched,
-                    imap_session,
-                    &matched,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.matched, &matched)?;
             }
             if !not_matched.is_empty() || check.empty_ok == MatchEmpty::NotMatched {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.not_matched,
-                    imap_session,
-                    &not_matched,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.not_matched, &not_matched)?;
             }
         }
         CheckerTree::BodyCheckRegex(check) => {
@@ -829,98 +1609,546 @@ fn run_check_tree(
             let mut not_matched = Vec::new();
 
             if !mails.is_empty() {
-                let mut mails_by_uid: HashMap<u32, &Mail> = HashMap::new();
-                for mail in mails {
-                    mails_by_uid.insert(u32::from(mail.uid), *mail);
+                (matched, not_matched) = split_by_regex(ctx, mails, &check.regex)?;
+            }
+
+            debug!(
+                "End of BodyCheckRegex for regex {}; {} mails matched and {} mails not_matched",
+                check.regex,
+                matched.len(),
+                not_matched.len()
+            );
+
+            // Dispatch the two lists down the tree
+            if !matched.is_empty() || check.empty_ok == MatchEmpty::Matched {
+                run_check_tree(ctx, name, &check.matched, &matched)?;
+            }
+            if !not_matched.is_empty() || check.empty_ok == MatchEmpty::NotMatched {
+                run_check_tree(ctx, name, &check.not_matched, &not_matched)?;
+            }
+        }
+        CheckerTree::RatioCheck(check) => {
+            let numerator = select(ctx, name, &check.numerator, mails)?.0.len();
+            let denominator = match &check.denominator {
+                Some(selection) => select(ctx, name, selection, mails)?.0.len(),
+                None => mails.len(),
+            };
+
+            // Nothing out of nothing is as low as it gets
+            #[allow(clippy::cast_precision_loss)]
+            let percent = if denominator == 0 {
+                0.0
+            } else {
+                100.0 * numerator as f64 / denominator as f64
+            };
+
+            debug!(
+                "RatioCheck for {:?} out of {:?}: {numerator} out of {denominator}, which is {percent:.1}%, against {}% to {:?}%",
+                check.numerator,
+                check.denominator,
+                check.min_percent,
+                check.max_percent
+            );
+
+            if percent < check.min_percent {
+                run_check_tree(ctx, name, &check.below, mails)?;
+            } else if check.max_percent.is_some_and(|x| percent > x) {
+                run_check_tree(ctx, name, &check.above, mails)?;
+            } else {
+                run_check_tree(ctx, name, &check.in_range, mails)?;
+            }
+        }
+
+        CheckerTree::ExtractCheck(check) => {
+            debug!(
+                "Start of ExtractCheck for {} {}, checking {} mails",
+                check.capture,
+                check.comparison,
+                mails.len()
+            );
+
+            let mut matched = Vec::new();
+            let mut not_matched = Vec::new();
+            let mut not_found = Vec::new();
+
+            if !mails.is_empty() {
+                let values = capture_numbers(ctx, &check.capture, mails)?;
+                for (mail, value) in mails.iter().zip(values) {
+                    match value {
+                        Some(value) if check.comparison.matches(value) => matched.push(*mail),
+                        Some(_) => not_matched.push(*mail),
+                        None => not_found.push(*mail),
+                    }
                 }
+            }
 
-                let uids: Vec<Uid> = mails.iter().map(|x| x.uid).collect();
-                let uids_list = uids
-                    .iter()
-                    .map(std::string::ToString::to_string)
-                    .collect::<Vec<_>>()
-                    .join(",");
-
-                let mail_bodies = imap_session
-                    .uid_fetch(&uids_list, "BODY.PEEK[TEXT]")
-                    .expect("Couldn't fetch mail for body check!");
-
-                if mail_bodies.len() != uids.len() {
-                    error!("Couldn't retrieve the right number of mails; count retrieved: {}, count requested: {}, uid list: {uids_list}.", mail_bodies.len(), uids.len());
-                    panic!();
-                }
-
-                for mail in mail_bodies.iter() {
-                    let uid: Uid = mail
-                        .uid
-                        .unwrap_or_else(|| panic!("Mail has no uid\n\nmail: {mail:?}"))
-                        .into();
-
-                    let assoc_mail = &mails_by_uid[&(u32::from(uid))];
-
-                    let body = match mail.text() {
-                    Some(x) => std::str::from_utf8(x)
-                        .unwrap_or_else(|err| {
-                            panic!(
-                                "Mail body was not valid utf-8\n\nerror is: {err}\n\nfrom_addr: {}, subject: {}, date: {}",
-                                assoc_mail.from_addr,
-                                assoc_mail.subject,
-                                assoc_mail.date
-                            )
-                        })
-                        .to_string(),
-                    None => {
-                        // Was not able to trigger this in testing without doing obviously wrong things
-                        // like not fetching the body at all.
-                        panic!(
-                            "Mail has no body\n\nfrom_addr: {}, subject: {}, date: {}",
-                            assoc_mail.from_addr,
-                            assoc_mail.subject,
-                            assoc_mail.date
-                        )
+            debug!(
+                "End of ExtractCheck for {} {}; {} mails matched, {} mails not_matched and {} mails not_found",
+                check.capture,
+                check.comparison,
+                matched.len(),
+                not_matched.len(),
+                not_found.len()
+            );
+
+            if !matched.is_empty() || check.empty_ok == MatchEmpty::Matched {
+                run_check_tree(ctx, name, &check.matched, &matched)?;
+            }
+            if !not_matched.is_empty() || check.empty_ok == MatchEmpty::NotMatched {
+                run_check_tree(ctx, name, &check.not_matched, &not_matched)?;
+            }
+            if !not_found.is_empty() {
+                run_check_tree(ctx, name, &check.not_found, &not_found)?;
+            }
+        }
+        CheckerTree::AggregateCheck(check) => {
+            debug!(
+                "Start of AggregateCheck for the {:?} of {}, checking {} mails",
+                check.aggregate,
+                check.capture,
+                mails.len()
+            );
+
+            let mut values = Vec::new();
+            if !mails.is_empty() {
+                let numbers = capture_numbers(ctx, &check.capture, mails)?;
+                for (mail, number) in mails.iter().zip(numbers) {
+                    if let Some(number) = number {
+                        values.push((mail.date, number));
                     }
-                };
+                }
+            }
+
+            let Some(result) = check.aggregate.apply(&values) else {
+                debug!(
+                    "End of AggregateCheck for the {:?} of {}; no numbers found",
+                    check.aggregate, check.capture
+                );
+                return run_check_tree(ctx, name, &check.not_found, mails);
+            };
+
+            let matched = check.comparison.matches(result);
+            debug!(
+                "End of AggregateCheck for the {:?} of {}; result is {result}, which is {}{}",
+                check.aggregate,
+                check.capture,
+                if matched { "" } else { "not " },
+                check.comparison
+            );
+
+            ctx.labels.push(format!(
+                "{:?} of {} = {result}",
+                check.aggregate, check.capture.name
+            ));
+            let result = run_check_tree(
+                ctx,
+                name,
+                if matched {
+                    &check.matched
+                } else {
+                    &check.not_matched
+                },
+                mails,
+            );
+            ctx.labels.pop();
+            result?;
+        }
+        CheckerTree::GroupBy(check) => {
+            debug!(
+                "Start of GroupBy for {}, checking {} mails",
+                check.key,
+                mails.len()
+            );
 
-                    if check.regex.is_match(&body) {
-                        matched.push(*assoc_mail);
+            // Sorted, so the groups always run in the same order
+            let mut groups: BTreeMap<String, Vec<&Mail>> = check
+                .expected_keys
+                .iter()
+                .map(|x| (x.clone(), Vec::new()))
+                .collect();
+            let mut no_key = Vec::new();
+
+            if !mails.is_empty() {
+                let texts = capture_texts(ctx, &check.key.field, mails)?;
+                for (mail, text) in mails.iter().zip(texts) {
+                    match text.as_deref().and_then(|x| check.key.find(x)) {
+                        Some(key) => groups.entry(key.to_string()).or_default().push(*mail),
+                        None => no_key.push(*mail),
+                    }
+                }
+            }
+
+            debug!(
+                "End of GroupBy for {}; {} groups, and {} mails with no key",
+                check.key,
+                groups.len(),
+                no_key.len()
+            );
+
+            for (key, group) in &groups {
+                ctx.labels.push(format!("{} = {key}", check.key.name));
+                let result = run_check_tree(ctx, name, &check.subtree, group);
+                ctx.labels.pop();
+                result?;
+            }
+
+            if !no_key.is_empty() {
+                if let Some(tree) = &check.no_key {
+                    run_check_tree(ctx, name, tree, &no_key)?;
+                } else {
+                    debug!(
+                        "GroupBy for {} has no no_key branch; dropping {} mails",
+                        check.key,
+                        no_key.len()
+                    );
+                }
+            }
+        }
+        CheckerTree::Correlate(check) => {
+            debug!(
+                "Start of Correlate for {}, checking {} mails",
+                check.key,
+                mails.len()
+            );
+
+            let (starts, rest) = select(ctx, name, &check.start, mails)?;
+            let (finishes, mut other) = select(ctx, name, &check.finish, &rest)?;
+
+            // Each key's starts and finishes
+            let mut by_key: BTreeMap<String, (Vec<&Mail>, Vec<&Mail>)> = BTreeMap::new();
+            for (group, is_start) in [(starts, true), (finishes, false)] {
+                if group.is_empty() {
+                    continue;
+                }
+                let texts = capture_texts(ctx, &check.key.field, &group)?;
+                for (mail, text) in group.into_iter().zip(texts) {
+                    match text.as_deref().and_then(|x| check.key.find(x)) {
+                        Some(key) => {
+                            let entry = by_key.entry(key.to_string()).or_default();
+                            if is_start {
+                                entry.0.push(mail);
+                            } else {
+                                entry.1.push(mail);
+                            }
+                        }
+                        None => other.push(mail),
+                    }
+                }
+            }
+
+            let now = ctx.clock.now();
+            let mut paired = Vec::new();
+            let mut timed_out = Vec::new();
+            let mut pending = Vec::new();
+            for (key, (mut key_starts, mut key_finishes)) in by_key {
+                key_starts.sort_by_key(|x| x.date);
+                key_finishes.sort_by_key(|x| x.date);
+
+                for start in key_starts {
+                    let finish = key_finishes.iter().position(|x| x.date >= start.date);
+                    if let Some(finish) = finish.map(|x| key_finishes.remove(x)) {
+                        let took = Interval(finish.date - start.date);
+                        for mail in [start, finish] {
+                            ctx.notes
+                                .entry(mail.uid)
+                                .or_default()
+                                .push(format!("{} = {key}, finished after {took}", check.key.name));
+                        }
+                        paired.push(start);
+                        paired.push(finish);
                     } else {
-                        not_matched.push(*assoc_mail);
+                        ctx.notes
+                            .entry(start.uid)
+                            .or_default()
+                            .push(format!("{} = {key}, not finished", check.key.name));
+                        if start
+                            .date
+                            .checked_add(check.timeout.0)
+                            .is_some_and(|x| x < now)
+                        {
+                            timed_out.push(start);
+                        } else {
+                            pending.push(start);
+                        }
                     }
                 }
+
+                // Finishes without a start
+                other.extend(key_finishes);
             }
 
             debug!(
-                "End of BodyCheckRegex for regex {}; {} mails matched and {} mails not_matched",
-                check.regex,
+                "End of Correlate for {}; {} mails paired, {} timed out, {} pending and {} other",
+                check.key,
+                paired.len(),
+                timed_out.len(),
+                pending.len(),
+                other.len()
+            );
+
+            run_check_tree(ctx, name, &check.paired, &paired)?;
+            if !timed_out.is_empty() {
+                run_check_tree(ctx, name, &check.timed_out, &timed_out)?;
+            }
+            for (tree, tree_name, group) in [
+                (&check.pending, "pending", pending),
+                (&check.other, "other", other),
+            ] {
+                if group.is_empty() {
+                    continue;
+                }
+                if let Some(tree) = tree {
+                    run_check_tree(ctx, name, tree, &group)?;
+                } else {
+                    debug!(
+                        "Correlate for {} has no {tree_name} branch; dropping {} mails",
+                        check.key,
+                        group.len()
+                    );
+                }
+            }
+        }
+        CheckerTree::Latest(check) => {
+            // Newest first; UIDs go up as mail arrives, so they break ties
+            let mut sorted = mails.clone();
+            sorted.sort_by_key(|x| std::cmp::Reverse((x.date, x.uid)));
+
+            let n = usize::try_from(check.n).unwrap_or(usize::MAX);
+            let rest = sorted.split_off(n.min(sorted.len()));
+            debug!(
+                "Latest {}: {} latest mails and {} others",
+                check.n,
+                sorted.len(),
+                rest.len()
+            );
+
+            run_check_tree(ctx, name, &check.latest, &sorted)?;
+            if !rest.is_empty() {
+                run_check_tree(ctx, name, &check.rest, &rest)?;
+            }
+        }
+        CheckerTree::DuplicateCheck(check) => {
+            // Oldest first, so the first of each key is the original
+            let mut sorted = mails.clone();
+            sorted.sort_by_key(|x| (x.date, x.uid));
+
+            let mut originals: Vec<&Mail> = Vec::new();
+            let mut duplicates = Vec::new();
+            let mut seen: HashMap<String, &Mail> = HashMap::new();
+            for mail in sorted {
+                let Some(key) =
+                    check
+                        .by
+                        .key_for(mail.message_id.as_deref(), &mail.subject, &mail.from_addr)
+                else {
+                    originals.push(mail);
+                    continue;
+                };
+
+                match seen.get(&key) {
+                    Some(original)
+                        if check
+                            .window
+                            .is_none_or(|window| mail.date - original.date <= window.0) =>
+                    {
+                        ctx.notes
+                            .entry(mail.uid)
+                            .or_default()
+                            .push(format!("duplicate of uid {}", original.uid));
+                        duplicates.push(mail);
+                    }
+                    _ => {
+                        seen.insert(key, mail);
+                        originals.push(mail);
+                    }
+                }
+            }
+            debug!(
+                "DuplicateCheck by {:?}: {} originals and {} duplicates",
+                check.by,
+                originals.len(),
+                duplicates.len()
+            );
+
+            run_check_tree(ctx, name, &check.originals, &originals)?;
+            if !duplicates.is_empty() {
+                run_check_tree(ctx, name, &check.duplicates, &duplicates)?;
+            }
+        }
+        CheckerTree::SizeCheck(check) => {
+            let mut below = Vec::new();
+            let mut in_range = Vec::new();
+            let mut above = Vec::new();
+            let mut unknown = Vec::new();
+            for mail in mails {
+                let Some(size) = mail.size else {
+                    warn!(
+                        "The server didn't give the size of the mail from '{}' with subject '{}', so SizeCheck can't check it",
+                        mail.from_addr, mail.subject
+                    );
+                    unknown.push(*mail);
+                    continue;
+                };
+                if size < check.min_bytes {
+                    below.push(*mail);
+                } else if check.max_bytes.is_some_and(|x| size > x) {
+                    above.push(*mail);
+                } else {
+                    in_range.push(*mail);
+                }
+                ctx.notes
+                    .entry(mail.uid)
+                    .or_default()
+                    .push(format!("{size} bytes"));
+            }
+            debug!(
+                "SizeCheck for {} to {:?} bytes; {} mails below, {} in range, {} above and {} of unknown size",
+                check.min_bytes,
+                check.max_bytes,
+                below.len(),
+                in_range.len(),
+                above.len(),
+                unknown.len()
+            );
+
+            run_check_tree(ctx, name, &check.in_range, &in_range)?;
+            for (tree, group) in [(&check.below, below), (&check.above, above)] {
+                if !group.is_empty() {
+                    run_check_tree(ctx, name, tree, &group)?;
+                }
+            }
+            if !unknown.is_empty() {
+                if let Some(tree) = &check.unknown {
+                    run_check_tree(ctx, name, tree, &unknown)?;
+                } else {
+                    debug!(
+                        "SizeCheck has no unknown branch; dropping {} mails",
+                        unknown.len()
+                    );
+                }
+            }
+        }
+        CheckerTree::AttachmentCheck(check) => {
+            let wanted = |part: &MailPart| {
+                part.attachment
+                    && check.filename.as_ref().is_none_or(|regex| {
+                        part.filename.as_deref().is_some_and(|x| regex.is_match(x))
+                    })
+                    && check
+                        .mime_type
+                        .as_ref()
+                        .is_none_or(|regex| regex.is_match(&part.mime_type))
+            };
+
+            let (matched, not_matched): (Vec<&Mail>, Vec<&Mail>) =
+                mails.iter().partition(|x| x.parts.iter().any(wanted));
+            debug!(
+                "AttachmentCheck for filename {:?} and mime_type {:?}; {} mails matched and {} mails not_matched",
+                check.filename,
+                check.mime_type,
                 matched.len(),
                 not_matched.len()
             );
 
-            // Dispatch the two lists down the tree
             if !matched.is_empty() || check.empty_ok == MatchEmpty::Matched {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.matched,
-                    imap_session,
-                    &matched,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.matched, &matched)?;
             }
             if !not_matched.is_empty() || check.empty_ok == MatchEmpty::NotMatched {
-                run_check_tree(
-                    noop,
-                    name,
-                    &check.not_matched,
-                    imap_session,
-                    &not_matched,
-                    gmail_delete_hack,
-                )?;
+                run_check_tree(ctx, name, &check.not_matched, &not_matched)?;
             }
         }
+        CheckerTree::ExpectSchedule(check) => {
+            debug!(
+                "Start of ExpectSchedule for schedule {}, checking {} mails",
+                check.cron,
+                mails.len()
+            );
+
+            let grace = check.grace.0;
+            let now = ctx.clock.now();
+            let window_start = now
+                .checked_sub(check.lookback.0)
+                .ok_or_else(|| MyError::ScheduleWindow(check.cron.to_string()))?;
+            // Start early enough to catch the mail from a run just before the window that
+            // arrived inside it
+            let runs = check.cron.instants_between(
+                window_start
+                    .checked_sub(grace)
+                    .ok_or_else(|| MyError::ScheduleWindow(check.cron.to_string()))?,
+                now,
+                ctx.clock,
+            );
+
+            let mut seen = vec![false; runs.len()];
+            let mut ok = Vec::new();
+            let mut extra = Vec::new();
+            for mail in mails {
+                if mail.date < window_start {
+                    ok.push(*mail);
+                    continue;
+                }
+
+                // A mail belongs to the latest run before it, if that was recent enough
+                match runs.iter().rposition(|x| *x <= mail.date) {
+                    Some(run) if mail.date <= runs[run] + grace => {
+                        trace!(
+                            "Mail uid {}, subject: {}, dated {:?} is from the run at {:?}",
+                            mail.uid,
+                            mail.subject,
+                            mail.date,
+                            runs[run]
+                        );
+                        seen[run] = true;
+                        ok.push(*mail);
+                    }
+                    _ => {
+                        trace!(
+                            "Mail uid {}, subject: {}, dated {:?} isn't from any run",
+                            mail.uid,
+                            mail.subject,
+                            mail.date
+                        );
+                        extra.push(*mail);
+                    }
+                }
+            }
 
+            // Runs that are still within their grace period haven't been missed yet
+            let missing: Vec<String> = runs
+                .iter()
+                .zip(seen)
+                .filter(|(run, seen)| !seen && **run >= window_start && **run + grace <= now)
+                .map(|(run, _)| run.to_string())
+                .collect();
+
+            debug!(
+                "End of ExpectSchedule for schedule {}; {} runs expected, {} missed, {} mails ok and {} unexpected",
+                check.cron,
+                runs.iter().filter(|x| **x >= window_start).count(),
+                missing.len(),
+                ok.len(),
+                extra.len()
+            );
+            if !missing.is_empty() {
+                info!(
+                    "No mail for check '{name}' from the scheduled run(s) at {}",
+                    missing.join(", ")
+                );
+            }
+
+            if !ok.is_empty() || missing.is_empty() {
+                run_check_tree(ctx, name, &check.ok, &ok)?;
+            }
+            if !missing.is_empty() {
+                run_check_tree(ctx, name, &check.missing, &Vec::new())?;
+            }
+            if !extra.is_empty() {
+                run_check_tree(ctx, name, &check.unexpected_extra, &extra)?;
+            }
+        }
         CheckerTree::CountCheck(check) => {
-            let cmp = mails.len().cmp(&usize::from(check.count));
+            let count = u32::try_from(mails.len()).unwrap_or(u32::MAX);
+            let cmp = count.cmp(&check.count);
             debug!(
                 "Start of CountCheck for number {}; actual number is {}, comparitor result is {cmp:#?}",
                 check.count,
@@ -929,38 +2157,1219 @@ fn run_check_tree(
 
             match cmp {
                 Ordering::Greater => {
-                    run_check_tree(
-                        noop,
-                        name,
-                        &check.greater_than,
-                        imap_session,
-                        mails,
-                        gmail_delete_hack,
-                    )?;
+                    run_check_tree(ctx, name, &check.greater_than, mails)?;
                 }
                 Ordering::Less => {
-                    run_check_tree(
-                        noop,
-                        name,
-                        &check.less_than,
-                        imap_session,
-                        mails,
-                        gmail_delete_hack,
-                    )?;
+                    run_check_tree(ctx, name, &check.less_than, mails)?;
                 }
                 Ordering::Equal => {
-                    run_check_tree(
-                        noop,
-                        name,
-                        &check.equal,
-                        imap_session,
-                        mails,
-                        gmail_delete_hack,
-                    )?;
+                    run_check_tree(ctx, name, &check.equal, mails)?;
                 }
-            };
+            }
+        }
+        CheckerTree::CountRangeCheck(check) => {
+            let count = u32::try_from(mails.len()).unwrap_or(u32::MAX);
+            debug!(
+                "CountRangeCheck for {} to {:?}; actual number is {count}",
+                check.min, check.max,
+            );
+
+            if count < check.min {
+                run_check_tree(ctx, name, &check.below, mails)?;
+            } else if check.max.is_some_and(|x| count > x) {
+                run_check_tree(ctx, name, &check.above, mails)?;
+            } else {
+                run_check_tree(ctx, name, &check.in_range, mails)?;
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod body_test {
+    use super::{contains_strings, searchable_text};
+
+    fn text(whole: &str) -> String {
+        searchable_text(&mailparse::parse_mail(whole.as_bytes()).unwrap())
+    }
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|x| (*x).to_string()).collect()
+    }
+
+    #[test]
+    fn test_quoted_printable() {
+        let body = text(
+            "Subject: Puppet\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\
+             \r\n\
+             Notice: Applied catalog in =\r\n\
+             12.3 seconds; caf=C3=A9 =3D open\r\n",
+        );
+
+        assert!(contains_strings(
+            &body,
+            &strings(&["Applied catalog in 12.3 seconds"]),
+            false,
+            false
+        ));
+        assert!(contains_strings(
+            &body,
+            &strings(&["café = open"]),
+            false,
+            false
+        ));
+        assert!(!contains_strings(
+            &body,
+            &strings(&["=C3=A9"]),
+            false,
+            false
+        ));
+    }
+
+    #[test]
+    fn test_base64() {
+        let body = text(
+            "Subject: Backup\r\n\
+             Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+             \r\n\
+             --b\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             QmFja3VwIGZpbmlzaGVkOiAxMiBmaWxlcywgw6l0YXQgT0sNCg==\r\n\
+             --b\r\n\
+             Content-Type: application/octet-stream\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             QmFja3VwIGZpbmlzaGVkIHNlY3JldGx5\r\n\
+             --b--\r\n",
+        );
+
+        assert!(contains_strings(
+            &body,
+            &strings(&["Backup finished", "12 files"]),
+            true,
+            false
+        ));
+        // Only text parts get looked through
+        assert!(!contains_strings(
+            &body,
+            &strings(&["secretly"]),
+            false,
+            false
+        ));
+        assert!(!contains_strings(
+            &body,
+            &strings(&["Backup finished", "secretly"]),
+            true,
+            false
+        ));
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let body = text(
+            "Subject: Backup\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             QmFja3VwIGZpbmlzaGVkOiAxMiBmaWxlcywgw6l0YXQgT0sNCg==\r\n",
+        );
+
+        assert!(!contains_strings(
+            &body,
+            &strings(&["ÉTAT ok"]),
+            false,
+            false
+        ));
+        assert!(contains_strings(&body, &strings(&["ÉTAT ok"]), false, true));
+        assert!(contains_strings(
+            &body,
+            &strings(&["backup FINISHED", "nowhere"]),
+            false,
+            true
+        ));
+        assert!(!contains_strings(
+            &body,
+            &strings(&["backup FINISHED", "nowhere"]),
+            true,
+            true
+        ));
+    }
+}
+
+// An IMAP server in memory, for running the parts of a session that talk to one.
+#[cfg(test)]
+mod fake_imap {
+    use super::{Context, Summary};
+    use amcheck::clock::Clock;
+    use amcheck::search::Literals;
+    use std::collections::{HashMap, VecDeque};
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    pub type Commands = Arc<Mutex<Vec<String>>>;
+
+    // Answers each command line the client sends with whatever `respond` gives for it (less the
+    // tag), followed by a tagged OK.  Any login is good.
+    struct FakeServer {
+        respond: Box<dyn FnMut(&str) -> String + Send>,
+        commands: Commands,
+        received: Vec<u8>,
+        replies: VecDeque<u8>,
+    }
+
+    impl Read for FakeServer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for FakeServer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.received.extend_from_slice(buf);
+            while let Some(end) = self.received.windows(2).position(|x| x == b"\r\n") {
+                let line: Vec<u8> = self.received.drain(..end + 2).collect();
+                let line = String::from_utf8_lossy(&line[..end]).into_owned();
+                let (tag, command) = line.split_once(' ').unwrap_or((&line, ""));
+
+                if !command.starts_with("LOGIN ") {
+                    let reply = (self.respond)(command);
+                    self.commands.lock().unwrap().push(command.to_string());
+                    self.replies.extend(reply.as_bytes());
+                }
+                self.replies
+                    .extend(format!("{tag} OK Done.\r\n").as_bytes());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl imap::extensions::idle::SetReadTimeout for FakeServer {
+        fn set_read_timeout(
+            &mut self,
+            _timeout: Option<std::time::Duration>,
+        ) -> imap::error::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A logged-in session with a server that answers commands with `respond`, and the commands
+    /// it's had since.
+    pub fn session(
+        respond: impl FnMut(&str) -> String + Send + 'static,
+    ) -> (imap::Session<Box<dyn imap::ImapConnection>>, Commands) {
+        let commands = Commands::default();
+        let server = FakeServer {
+            respond: Box::new(respond),
+            commands: commands.clone(),
+            received: Vec::new(),
+            replies: VecDeque::new(),
+        };
+
+        let session = imap::Client::new(Box::new(server) as Box<dyn imap::ImapConnection>)
+            .login("login", "password")
+            .map_err(|(err, _)| err)
+            .unwrap();
+
+        (session, commands)
+    }
+
+    /// A session with a server that doesn't expect to be asked anything.
+    pub fn quiet_session() -> imap::Session<Box<dyn imap::ImapConnection>> {
+        session(|command| panic!("Unexpected IMAP command {command}")).0
+    }
+
+    /// The untagged response to a FETCH of the ENVELOPE, INTERNALDATE, RFC822.SIZE and
+    /// BODYSTRUCTURE of a plain text mail.  `date` is as in a Date header, and `internal_date` as
+    /// in INTERNALDATE.
+    pub fn fetched(seq: u32, uid: u32, subject: &str, date: &str, internal_date: &str) -> String {
+        format!(
+            "* {seq} FETCH (UID {uid} RFC822.SIZE 1000 INTERNALDATE \"{internal_date}\" \
+             ENVELOPE (\"{date}\" \"{subject}\" ((\"Cron Daemon\" NIL \"root\" \"example.com\")) \
+             NIL NIL NIL NIL NIL NIL \"<{uid}@example.com>\") \
+             BODYSTRUCTURE (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 1000 20))\r\n"
+        )
+    }
+
+    /// The untagged response to a FETCH of the whole of a mail.
+    pub fn fetched_body(seq: u32, uid: u32, body: &str) -> String {
+        format!(
+            "* {seq} FETCH (UID {uid} BODY[] {{{}}}\r\n{body})\r\n",
+            body.len()
+        )
+    }
+
+    /// The UIDs in a UID set like "1:3,7", in order.
+    pub fn uid_list(set: &str) -> Vec<u32> {
+        set.split(',')
+            .flat_map(|x| match x.split_once(':') {
+                Some((first, last)) => {
+                    (first.parse::<u32>().unwrap()..=last.parse().unwrap()).collect()
+                }
+                None => vec![x.parse().unwrap()],
+            })
+            .collect()
+    }
+
+    /// A fresh context for a run against the session.
+    pub fn context<'a>(
+        imap_session: &'a mut imap::Session<Box<dyn imap::ImapConnection>>,
+        clock: &'a Clock,
+    ) -> Context<'a> {
+        Context {
+            imap_session,
+            noop: false,
+            gmail_delete_hack: false,
+            uid_chunk_size: 1000,
+            clock,
+            summary: Summary::default(),
+            moved_mails: HashMap::new(),
+            notes: HashMap::new(),
+            labels: Vec::new(),
+            literals: Literals::Any,
+            qresync: false,
+            folder: String::new(),
+            bodies: HashMap::new(),
+            envelope_cache: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod session_test {
+    use super::fake_imap::{context, fetched, session, uid_list, Commands};
+    use super::{check_storage, folder_mails, move_to_storage, Mail};
+    use amcheck::clock::Clock;
+    use amcheck::configuration::{Account, Handler};
+    use amcheck::envelope_cache::EnvelopeCache;
+    use std::collections::HashMap;
+    use time::macros::{date, datetime};
+
+    fn account(handlers: &str) -> Account {
+        Account {
+            name: "test".to_string(),
+            imapserver: "localhost".to_string(),
+            login: "login".to_string(),
+            password: secrecy::Secret::new("password".to_string()),
+            handlers: serde_json::from_str::<Vec<Handler>>(handlers).unwrap(),
+            source_folders: vec!["INBOX".to_string()],
+            storage_folder_name: "amcheck_storage".to_string(),
+            days_back: 60,
+            gmail_delete_hack: false,
+            uid_chunk_size: 1000,
+            envelope_cache_dir: None,
+        }
+    }
+
+    // A session with a server that has these mails (by UID and subject) in each folder, and moves
+    // them between folders as it's asked to
+    fn server(
+        mut folders: HashMap<String, Vec<(u32, &'static str)>>,
+    ) -> (imap::Session<Box<dyn imap::ImapConnection>>, Commands) {
+        const DATE: &str = "Mon, 11 Mar 2024 09:00:00 +0000";
+        const INTERNAL_DATE: &str = "11-Mar-2024 09:00:00 +0000";
+        let mut selected = String::new();
+        session(move |command| {
+            if let Some(folder) = command.strip_prefix("SELECT ") {
+                selected = folder.trim_matches('"').to_string();
+                return format!(
+                    "* {} EXISTS\r\n* OK [UIDVALIDITY 1] UIDs valid\r\n",
+                    folders[&selected].len()
+                );
+            }
+            if let Some(folder) = command.strip_prefix("LIST \"\" ") {
+                return if folders.contains_key(folder) {
+                    format!("* LIST () \"/\" {folder}\r\n")
+                } else {
+                    String::new()
+                };
+            }
+            if let Some((set, to)) = command
+                .strip_prefix("UID MOVE ")
+                .and_then(|x| x.split_once(' '))
+            {
+                let from = folders.get_mut(&selected).unwrap();
+                let moving: Vec<_> = uid_list(set)
+                    .into_iter()
+                    .map(|uid| from.remove(from.iter().position(|x| x.0 == uid).unwrap()))
+                    .collect();
+
+                let to = folders.get_mut(to.trim_matches('"')).unwrap();
+                let mut new_uids = Vec::new();
+                for (_, subject) in moving {
+                    let uid = to.iter().map(|(uid, _)| uid + 1).max().unwrap_or(1);
+                    to.push((uid, subject));
+                    new_uids.push(uid.to_string());
+                }
+                // UIDPLUS says where they went
+                return format!("* OK [COPYUID 1 {set} {}] Moved.\r\n", new_uids.join(","));
+            }
+
+            let mails = &folders[&selected];
+            if command.starts_with("UID SEARCH ") {
+                // Everything's recent enough
+                let uids: Vec<String> = mails.iter().map(|(uid, _)| uid.to_string()).collect();
+                return format!("* SEARCH {}\r\n", uids.join(" "));
+            }
+            if let Some(set) = command
+                .strip_prefix("UID FETCH ")
+                .and_then(|x| x.split(' ').next())
+            {
+                let uids = uid_list(set);
+                return (1..)
+                    .zip(mails)
+                    .filter(|(_, (uid, _))| uids.contains(uid))
+                    .map(|(seq, (uid, subject))| fetched(seq, *uid, subject, DATE, INTERNAL_DATE))
+                    .collect();
+            }
+            panic!("Unexpected IMAP command {command}");
+        })
+    }
+
+    #[test]
+    fn test_move_then_check() {
+        let (mut session, commands) = server(HashMap::from([
+            (
+                "INBOX".to_string(),
+                vec![
+                    (1, "puppet ok"),
+                    (2, "puppet failed"),
+                    (3, "something else"),
+                ],
+            ),
+            ("amcheck_storage".to_string(), vec![(7, "puppet old")]),
+        ]));
+        let account = account(
+            r#"[
+              {
+                "name": "puppet",
+                "filters": [ { "Match": { "Subject": "^puppet" } } ],
+                "checker_tree": {
+                  "CountCheck": {
+                    "count": 3,
+                    "greater_than": { "Action": "Alert" },
+                    "less_than": { "Action": "Alert" },
+                    "equal": { "Action": "Success" }
+                  }
+                }
+              }
+            ]"#,
+        );
+        let clock = Clock::fixed(datetime!(2024-03-12 09:00 UTC));
+        let mut ctx = context(&mut session, &clock);
+
+        move_to_storage(&mut ctx, &account).unwrap();
+        let handlers = account.handlers.iter().collect::<Vec<_>>();
+        check_storage(&mut ctx, &handlers, "amcheck_storage").unwrap();
+
+        assert_eq!(ctx.summary.moved, 2);
+        assert_eq!(ctx.summary.passed, 1);
+        assert!(ctx.summary.failed.is_empty());
+        // The moved mails were checked under their new UIDs, without fetching them again
+        let fetches: Vec<Vec<u32>> = commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|x| x.strip_prefix("UID FETCH "))
+            .map(|x| {
+                let mut uids = uid_list(x.split(' ').next().unwrap());
+                uids.sort_unstable();
+                uids
+            })
+            .collect();
+        assert_eq!(fetches, vec![vec![1, 2, 3], vec![7]]);
+    }
+
+    // Runs the move phase's fetch of INBOX against a server that has the mails that `respond`
+    // gives, with the envelope cache in `dir`, and returns the UIDs it finds.
+    fn inbox_uids(
+        dir: &std::path::Path,
+        qresync: bool,
+        since: time::Date,
+        mut respond: impl FnMut(&str) -> String + Send + 'static,
+    ) -> Vec<u32> {
+        let (mut session, _) = session(move |command| {
+            if command == "SELECT \"INBOX\"" {
+                "* OK [UIDVALIDITY 1] UIDs valid\r\n* OK [HIGHESTMODSEQ 100] Ok\r\n".to_string()
+            } else {
+                respond(command)
+            }
+        });
+        let mailbox = session.select("INBOX").unwrap();
+        let clock = Clock::fixed(datetime!(2024-03-12 09:00 UTC));
+        let mut ctx = context(&mut session, &clock);
+        ctx.qresync = qresync;
+        ctx.envelope_cache = Some(EnvelopeCache::load(dir, "test"));
+
+        folder_mails(&mut ctx, "INBOX", &mailbox, Some(since))
+            .unwrap()
+            .into_iter()
+            .map(|x| u32::from(x.uid))
+            .collect()
+    }
+
+    #[test]
+    fn test_incremental_window() {
+        let dir = std::env::temp_dir().join(format!("amcheck-window-{}", std::process::id()));
+        // The Date header is the sender's idea of the time, so the server goes by when it got
+        // the mail, as should we
+        let inbox = |uid| match uid {
+            1 => fetched(
+                1,
+                1,
+                "old",
+                "Fri, 1 Mar 2024 09:00:00 +0000",
+                "01-Mar-2024 09:00:00 +0000",
+            ),
+            2 => fetched(
+                2,
+                2,
+                "held up",
+                "Sun, 10 Mar 2024 09:00:00 +0000",
+                "01-Mar-2024 09:00:00 +0000",
+            ),
+            3 => fetched(
+                3,
+                3,
+                "new",
+                "Mon, 11 Mar 2024 09:00:00 +0000",
+                "11-Mar-2024 09:00:00 +0000",
+            ),
+            4 => fetched(
+                4,
+                4,
+                "clock wrong",
+                "Fri, 1 Mar 2024 09:00:00 +0000",
+                "11-Mar-2024 09:00:00 +0000",
+            ),
+            5 => fetched(
+                5,
+                5,
+                "newer",
+                "Tue, 12 Mar 2024 08:00:00 +0000",
+                "12-Mar-2024 08:00:00 +0000",
+            ),
+            _ => unreachable!(),
+        };
+        let since = date!(2024 - 03 - 05);
+
+        // Nothing in the cache yet, so the server does the search
+        let uids = inbox_uids(&dir, true, since, move |command| match command {
+            "UID SEARCH SINCE 05-Mar-2024" => "* SEARCH 3 4\r\n".to_string(),
+            "UID FETCH 3:4 (ENVELOPE INTERNALDATE RFC822.SIZE BODYSTRUCTURE)" => {
+                inbox(3) + &inbox(4)
+            }
+            _ => panic!("Unexpected IMAP command {command}"),
+        });
+        assert_eq!(uids, vec![3, 4]);
+
+        // The old mails have had their flags changed, so they come back along with the new one,
+        // but shouldn't be picked
+        let uids = inbox_uids(&dir, true, since, move |command| match command {
+            "UID FETCH 1:* (UID) (CHANGEDSINCE 100 VANISHED)" => {
+                "* 1 FETCH (UID 1)\r\n* 2 FETCH (UID 2)\r\n* 5 FETCH (UID 5)\r\n".to_string()
+            }
+            "UID FETCH 1:2,5 (ENVELOPE INTERNALDATE RFC822.SIZE BODYSTRUCTURE)" => {
+                inbox(1) + &inbox(2) + &inbox(5)
+            }
+            _ => panic!("Unexpected IMAP command {command}"),
+        });
+        assert_eq!(uids, vec![3, 4, 5]);
+
+        // Searching picks the same ones, without fetching anything, since they're all cached
+        let uids = inbox_uids(&dir, false, since, |command| match command {
+            "UID SEARCH SINCE 05-Mar-2024" => "* SEARCH 3 4 5\r\n".to_string(),
+            _ => panic!("Unexpected IMAP command {command}"),
+        });
+        assert_eq!(uids, vec![3, 4, 5]);
+
+        // A week later, with nothing new, everything's too old, including for the cache
+        let later = date!(2024 - 03 - 13);
+        let uids = inbox_uids(&dir, true, later, |command| match command {
+            "UID FETCH 1:* (UID) (CHANGEDSINCE 100 VANISHED)" => String::new(),
+            _ => panic!("Unexpected IMAP command {command}"),
+        });
+        let mut cache: EnvelopeCache<Mail> = EnvelopeCache::load(&dir, "test");
+        let (cached, _) = cache.take("INBOX", 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(uids.is_empty());
+        assert!(cached.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tree_test {
+    use super::fake_imap::{context, fetched_body, quiet_session, session, uid_list};
+    use super::{run_check_tree, Mail, Summary};
+    use amcheck::clock::Clock;
+    use amcheck::configuration::CheckerTree;
+    use std::sync::{Arc, Mutex};
+    use time::macros::datetime;
+    use time::OffsetDateTime;
+    use tracing::field::{Field, Visit};
+    use tracing::Level;
+    use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+
+    const ALERT: &str = r#"{ Action: "Alert" }"#;
+    const SUCCESS: &str = r#"{ Action: "Success" }"#;
+    const DELETE: &str = r#"{ Action: "Delete" }"#;
+    const NOW: OffsetDateTime = datetime!(2024-03-12 09:00 UTC);
+
+    fn mail(uid: u32, subject: &str, date: OffsetDateTime) -> Mail {
+        Mail {
+            uid: uid.into(),
+            subject: subject.to_string(),
+            from_addr: "root@example.com".to_string(),
+            date,
+            internal_date: None,
+            message_id: None,
+            size: Some(1000),
+            parts: Vec::new(),
+        }
+    }
+
+    fn mails(subjects: &[&str]) -> Vec<Mail> {
+        (1..)
+            .zip(subjects)
+            .map(|(uid, subject)| mail(uid, subject, NOW))
+            .collect()
+    }
+
+    // Keeps the message of everything logged at info and above
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct Message<'a>(&'a mut String);
+
+    impl Visit for Message<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                *self.0 = format!("{value:?}");
+            }
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for Recorder {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: layer::Context<'_, S>) {
+            if *event.metadata().level() <= Level::INFO {
+                let mut message = String::new();
+                event.record(&mut Message(&mut message));
+                self.0.lock().unwrap().push(message);
+            }
+        }
+    }
+
+    // What a run of a tree counted, and what it logged at info and above
+    struct Outcome {
+        summary: Summary,
+        log: Vec<String>,
+    }
+
+    fn run(tree: &str, mails: &[Mail]) -> Outcome {
+        run_on(quiet_session(), tree, mails)
+    }
+
+    // As run, against the given session.  It's in noop mode, so deleting just logs.
+    fn run_on(
+        mut session: imap::Session<Box<dyn imap::ImapConnection>>,
+        tree: &str,
+        mails: &[Mail],
+    ) -> Outcome {
+        let tree: CheckerTree = json5::from_str(tree).unwrap();
+        let clock = Clock::fixed(NOW);
+        let mut ctx = context(&mut session, &clock);
+        ctx.noop = true;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(Recorder(Arc::clone(&log)));
+        tracing::subscriber::with_default(subscriber, || {
+            run_check_tree(&mut ctx, "test", &tree, &mails.iter().collect()).unwrap();
+        });
+
+        let log = log.lock().unwrap().clone();
+        Outcome {
+            summary: ctx.summary,
+            log,
+        }
+    }
+
+    // The log of an alert on these mails, with the labels the check is under
+    fn alerted(labels: &str, mails: &[&Mail]) -> Vec<String> {
+        let noted: Vec<(&Mail, &str)> = mails.iter().map(|x| (*x, "")).collect();
+        alerted_noted(labels, &noted)
+    }
+
+    // As alerted, with the notes on each mail
+    fn alerted_noted(labels: &str, mails: &[(&Mail, &str)]) -> Vec<String> {
+        let mut log = vec![format!(
+            "CHECK FAILED for check 'test'{labels} for {} mails; details for first 10 (or fewer) mails follows",
+            mails.len()
+        )];
+        for (mail, notes) in mails {
+            log.push(format!(
+                "CHECK FAILED DETAILS for check 'test': mail from '{}' with subject '{}' and date '{}'{notes}!",
+                mail.from_addr, mail.subject, mail.date
+            ));
+        }
+        log
+    }
+
+    fn passed(labels: &str, count: usize) -> Vec<String> {
+        vec![format!("Check 'test'{labels} passed with {count} mails")]
+    }
+
+    fn deleted(count: usize) -> Vec<String> {
+        vec![format!(
+            "In noop mode, not deleting {count} mails for check 'test'"
+        )]
+    }
+
+    #[test]
+    fn test_count_range() {
+        let tree = format!(
+            "{{ CountRangeCheck: {{ min: 2, max: 3, below: {ALERT}, in_range: {SUCCESS}, above: {DELETE} }} }}"
+        );
+        let all = mails(&["a", "b", "c", "d"]);
+
+        for count in 0..=4 {
+            let mails: Vec<&Mail> = all[..count].iter().collect();
+            let outcome = run(&tree, &all[..count]);
+            match count {
+                0 | 1 => {
+                    assert_eq!(outcome.summary.passed, 0, "{count} mails");
+                    assert_eq!(outcome.summary.failed, vec!["test"], "{count} mails");
+                    assert_eq!(outcome.log, alerted("", &mails), "{count} mails");
+                }
+                2 | 3 => {
+                    assert_eq!(outcome.summary.passed, 1, "{count} mails");
+                    assert!(outcome.summary.failed.is_empty(), "{count} mails");
+                    assert_eq!(outcome.log, passed("", count), "{count} mails");
+                }
+                _ => {
+                    assert_eq!(outcome.summary.passed, 0, "{count} mails");
+                    assert!(outcome.summary.failed.is_empty(), "{count} mails");
+                    assert_eq!(outcome.log, deleted(count), "{count} mails");
+                }
+            }
+        }
+
+        // With no minimum or maximum, anything goes, including nothing
+        let tree = format!(
+            "{{ CountRangeCheck: {{ below: {ALERT}, in_range: {SUCCESS}, above: {ALERT} }} }}"
+        );
+        let outcome = run(&tree, &[]);
+        assert_eq!(outcome.summary.passed, 1);
+        assert!(outcome.summary.failed.is_empty());
+        assert_eq!(outcome.log, passed("", 0));
+    }
+
+    #[test]
+    fn test_ratio() {
+        let ratio = |denominator: &str| {
+            format!(
+                r#"{{ RatioCheck: {{
+                  numerator: {{ Matchers: [ {{ Match: {{ Subject: "^fail" }} }} ] }},
+                  {denominator}
+                  min_percent: 25,
+                  max_percent: 50,
+                  below: {SUCCESS},
+                  in_range: {DELETE},
+                  above: {ALERT},
+                }} }}"#
+            )
+        };
+
+        // Out of 4, 1 and 2 are 25% and 50%, both in range
+        let tree = ratio("");
+        let all = mails(&["ok 1", "ok 2", "ok 3", "ok 4"]);
+        let outcome = run(&tree, &all);
+        assert_eq!(outcome.summary.passed, 1);
+        assert!(outcome.summary.failed.is_empty());
+        assert_eq!(outcome.log, passed("", 4));
+        for subjects in [
+            ["fail 1", "ok 2", "ok 3", "ok 4"],
+            ["fail 1", "fail 2", "ok 3", "ok 4"],
+        ] {
+            let outcome = run(&tree, &mails(&subjects));
+            assert_eq!(outcome.summary.passed, 0, "{subjects:?}");
+            assert!(outcome.summary.failed.is_empty(), "{subjects:?}");
+            assert_eq!(outcome.log, deleted(4), "{subjects:?}");
+        }
+        let all = mails(&["fail 1", "fail 2", "fail 3", "ok 4"]);
+        let outcome = run(&tree, &all);
+        assert_eq!(outcome.summary.passed, 0);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(outcome.log, alerted("", &all.iter().collect::<Vec<_>>()));
+
+        // The denominator narrows down what it's out of, but all the mails go down the tree
+        let tree = ratio(r#"denominator: { Matchers: [ { Match: { Subject: "^(fail|ok)" } } ] },"#);
+        let all = mails(&["fail 1", "fail 2", "fail 3", "ok 4", "other"]);
+        let outcome = run(&tree, &all);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(outcome.log, alerted("", &all.iter().collect::<Vec<_>>()));
+
+        // No mails at all, or none in the denominator, is 0%
+        let outcome = run(&ratio(""), &[]);
+        assert_eq!(outcome.summary.passed, 1);
+        assert_eq!(outcome.log, passed("", 0));
+        let tree = ratio(r#"denominator: { Matchers: [ { Match: { Subject: "^ok" } } ] },"#);
+        let outcome = run(&tree, &mails(&["fail 1", "other"]));
+        assert_eq!(outcome.summary.passed, 1);
+        assert!(outcome.summary.failed.is_empty());
+        assert_eq!(outcome.log, passed("", 2));
+    }
+
+    // Passes if it gets two mails, alerts otherwise
+    const TWO: &str = r#"{ CountCheck: {
+      count: 2,
+      less_than: { Action: "Alert" },
+      greater_than: { Action: "Alert" },
+      equal: { Action: "Success" },
+    } }"#;
+
+    #[test]
+    fn test_group_by() {
+        let group_by = |extra: &str| {
+            format!(
+                r#"{{ GroupBy: {{
+                  key: {{ field: "Subject", regex: "^Cron <root@(?<host>[^>]+)>" }},
+                  subtree: {TWO},
+                  {extra}
+                }} }}"#
+            )
+        };
+        let all = mails(&[
+            "Cron <root@web1> puppet 1",
+            "Cron <root@web2> puppet 1",
+            "Something else",
+            "Cron <root@db1> puppet 1",
+            "Cron <root@web2> puppet 2",
+            "Cron <root@web1> puppet 2",
+        ]);
+
+        // Each host's mails go down the subtree together, in order of host, including an
+        // expected one with no mails; then the one without a host goes down no_key
+        let tree = group_by(&format!(
+            r#"expected_keys: [ "web1", "mail1" ], no_key: {ALERT},"#
+        ));
+        let outcome = run(&tree, &all);
+        assert_eq!(outcome.summary.passed, 2);
+        assert_eq!(outcome.summary.failed, vec!["test"; 3]);
+        assert_eq!(
+            outcome.log,
+            [
+                alerted(" (host = db1)", &[&all[3]]),
+                alerted(" (host = mail1)", &[]),
+                passed(" (host = web1)", 2),
+                passed(" (host = web2)", 2),
+                alerted("", &[&all[2]]),
+            ]
+            .concat()
+        );
+
+        // Without no_key, mails without a key are dropped
+        let outcome = run(&group_by(""), &all);
+        assert_eq!(outcome.summary.passed, 2);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(
+            outcome.log,
+            [
+                alerted(" (host = db1)", &[&all[3]]),
+                passed(" (host = web1)", 2),
+                passed(" (host = web2)", 2),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_group_by_header() {
+        let tree = format!(
+            r#"{{ GroupBy: {{
+              key: {{ field: {{ Header: "X-Host" }}, regex: "(?<host>.+)" }},
+              subtree: {TWO},
+              no_key: {ALERT},
+            }} }}"#
+        );
+        let all = mails(&["web1 1", "no header", "web1 2"]);
+        let bodies = [
+            (1, "X-Host: web1\nSubject: web1 1\n\nHello\n"),
+            (2, "Subject: no header\n\nHello\n"),
+            (3, "X-Host: web1\nSubject: web1 2\n\nHello\n"),
+        ];
+        let (session, _) = session(move |command| {
+            let Some(set) = command
+                .strip_prefix("UID FETCH ")
+                .and_then(|x| x.strip_suffix(" BODY.PEEK[]"))
+            else {
+                panic!("Unexpected IMAP command {command}");
+            };
+            uid_list(set)
+                .into_iter()
+                .map(|uid| {
+                    let (_, body) = bodies.iter().find(|x| x.0 == uid).unwrap();
+                    fetched_body(uid, uid, &body.replace('\n', "\r\n"))
+                })
+                .collect()
+        });
+
+        let outcome = run_on(session, &tree, &all);
+        assert_eq!(outcome.summary.passed, 1);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(
+            outcome.log,
+            [passed(" (host = web1)", 2), alerted("", &[&all[1]])].concat()
+        );
+    }
+
+    #[test]
+    fn test_correlate() {
+        let correlate = |paired: &str, timed_out: &str, extra: &str| {
+            format!(
+                r#"{{ Correlate: {{
+                  start: {{ Matchers: [ {{ Match: {{ Subject: "^Started" }} }} ] }},
+                  finish: {{ Matchers: [ {{ Match: {{ Subject: "^Finished" }} }} ] }},
+                  key: {{ field: "Subject", regex: "job (?<job>[0-9]+)" }},
+                  timeout: "2h",
+                  paired: {paired},
+                  timed_out: {timed_out},
+                  {extra}
+                }} }}"#
+            )
+        };
+        let mails = [
+            mail(1, "Started job 1", datetime!(2024-03-12 06:00 UTC)),
+            mail(2, "Finished job 1", datetime!(2024-03-12 06:30 UTC)),
+            // Long gone
+            mail(3, "Started job 2", datetime!(2024-03-12 05:00 UTC)),
+            // Still has time
+            mail(4, "Started job 3", datetime!(2024-03-12 08:00 UTC)),
+            mail(5, "Finished job 4", datetime!(2024-03-12 07:00 UTC)),
+            mail(6, "Something else", datetime!(2024-03-12 07:00 UTC)),
+            // A finish from before the start doesn't count, and the start is exactly at the
+            // timeout, which isn't past it
+            mail(7, "Started job 5", datetime!(2024-03-12 07:00 UTC)),
+            mail(8, "Finished job 5", datetime!(2024-03-12 06:00 UTC)),
+            // Finishing late still pairs up
+            mail(9, "Started job 6", datetime!(2024-03-12 01:00 UTC)),
+            mail(10, "Finished job 6", datetime!(2024-03-12 08:00 UTC)),
+            mail(
+                11,
+                "Started job without a number",
+                datetime!(2024-03-12 08:00 UTC),
+            ),
+        ];
+
+        let tree = correlate(ALERT, ALERT, &format!("pending: {ALERT}, other: {ALERT},"));
+        let outcome = run(&tree, &mails);
+        assert_eq!(outcome.summary.passed, 0);
+        assert_eq!(outcome.summary.failed, vec!["test"; 4]);
+        assert_eq!(
+            outcome.log,
+            [
+                alerted_noted(
+                    "",
+                    &[
+                        (&mails[0], " (job = 1, finished after 30m)"),
+                        (&mails[1], " (job = 1, finished after 30m)"),
+                        (&mails[8], " (job = 6, finished after 7h)"),
+                        (&mails[9], " (job = 6, finished after 7h)"),
+                    ]
+                ),
+                alerted_noted("", &[(&mails[2], " (job = 2, not finished)")]),
+                alerted_noted(
+                    "",
+                    &[
+                        (&mails[3], " (job = 3, not finished)"),
+                        (&mails[6], " (job = 5, not finished)"),
+                    ]
+                ),
+                alerted("", &[&mails[5], &mails[10], &mails[4], &mails[7]]),
+            ]
+            .concat()
+        );
+
+        // Without pending and other, those mails are dropped
+        let outcome = run(&correlate(SUCCESS, ALERT, ""), &mails[..4]);
+        assert_eq!(outcome.summary.passed, 1);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(
+            outcome.log,
+            [
+                passed("", 2),
+                alerted_noted("", &[(&mails[2], " (job = 2, not finished)")]),
+            ]
+            .concat()
+        );
+
+        // With nothing paired, paired still runs, but timed_out doesn't
+        let outcome = run(&correlate(SUCCESS, ALERT, ""), &mails[3..6]);
+        assert_eq!(outcome.summary.passed, 1);
+        assert!(outcome.summary.failed.is_empty());
+        assert_eq!(outcome.log, passed("", 0));
+    }
+
+    #[test]
+    fn test_latest() {
+        let latest =
+            |n: u32| format!("{{ Latest: {{ n: {n}, latest: {SUCCESS}, rest: {ALERT} }} }}");
+        // The two newest arrived at the same time, so the later UID is newer
+        let mails = [
+            mail(2, "tie low", datetime!(2024-03-12 08:00 UTC)),
+            mail(1, "old", datetime!(2024-03-12 06:00 UTC)),
+            mail(3, "tie high", datetime!(2024-03-12 08:00 UTC)),
+        ];
+
+        let outcome = run(&latest(1), &mails);
+        assert_eq!(outcome.summary.passed, 1);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(
+            outcome.log,
+            [passed("", 1), alerted("", &[&mails[0], &mails[1]])].concat()
+        );
+        let outcome = run(&latest(2), &mails);
+        assert_eq!(outcome.summary.passed, 1);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(
+            outcome.log,
+            [passed("", 2), alerted("", &[&mails[1]])].concat()
+        );
+
+        // Asking for more than there are gets them all, and nothing goes down rest
+        let outcome = run(&latest(5), &mails);
+        assert_eq!(outcome.summary.passed, 1);
+        assert!(outcome.summary.failed.is_empty());
+        assert_eq!(outcome.log, passed("", 3));
+        let outcome = run(&latest(1), &[]);
+        assert_eq!(outcome.summary.passed, 1);
+        assert!(outcome.summary.failed.is_empty());
+        assert_eq!(outcome.log, passed("", 0));
+    }
+
+    #[test]
+    fn test_attachment() {
+        use super::MailPart;
+
+        let part = |mime_type: &str, filename: Option<&str>, attachment: bool| MailPart {
+            mime_type: mime_type.to_string(),
+            filename: filename.map(str::to_string),
+            attachment,
+        };
+        let with_parts = |uid: u32, subject: &str, parts: Vec<MailPart>| Mail {
+            parts,
+            ..mail(uid, subject, NOW)
+        };
+        let mails = [
+            with_parts(
+                1,
+                "report",
+                vec![
+                    part("text/plain", None, false),
+                    part("application/pdf", Some("report.pdf"), true),
+                ],
+            ),
+            // Inline images in an HTML mail aren't attachments
+            with_parts(
+                2,
+                "newsletter",
+                vec![
+                    part("text/html", None, false),
+                    part("image/png", None, false),
+                ],
+            ),
+            with_parts(
+                3,
+                "photo",
+                vec![
+                    part("text/plain", None, false),
+                    part("image/jpeg", Some("photo.jpg"), true),
+                ],
+            ),
+            with_parts(4, "zip", vec![part("application/zip", None, true)]),
+            with_parts(5, "plain", vec![part("text/plain", None, false)]),
+        ];
+        let [report, newsletter, photo, zip, plain] = &mails;
+        let attachment = |filter: &str| {
+            format!(
+                r#"{{ AttachmentCheck: {{
+                  {filter}
+                  empty_ok: "Matched",
+                  matched: {ALERT},
+                  not_matched: {SUCCESS},
+                }} }}"#
+            )
+        };
+
+        for (filter, matched, not_matched) in [
+            ("", vec![report, photo, zip], vec![newsletter, plain]),
+            (
+                r#"mime_type: "^image/","#,
+                vec![photo],
+                vec![report, newsletter, zip, plain],
+            ),
+            // An attachment without a filename doesn't match any filename
+            (
+                r#"filename: ".","#,
+                vec![report, photo],
+                vec![newsletter, zip, plain],
+            ),
+        ] {
+            let outcome = run(&attachment(filter), &mails);
+            assert_eq!(outcome.summary.passed, 1, "{filter}");
+            assert_eq!(outcome.summary.failed, vec!["test"], "{filter}");
+            assert_eq!(
+                outcome.log,
+                [alerted("", &matched), passed("", not_matched.len())].concat(),
+                "{filter}"
+            );
+        }
+
+        // Nothing at all counts as matched, here
+        let outcome = run(&attachment(r#"filename: "\\.xlsx$","#), &[]);
+        assert_eq!(outcome.summary.passed, 0);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(outcome.log, alerted("", &[]));
+    }
+
+    #[test]
+    fn test_size() {
+        let size_check = |extra: &str| {
+            format!(
+                "{{ SizeCheck: {{ min_bytes: 500, max_bytes: 2000, below: {ALERT}, in_range: {SUCCESS}, above: {DELETE}, {extra} }} }}"
+            )
+        };
+        let with_size = |uid: u32, subject: &str, size: Option<u32>| Mail {
+            size,
+            ..mail(uid, subject, NOW)
+        };
+        let mails = [
+            with_size(1, "small", Some(100)),
+            with_size(2, "medium", Some(1000)),
+            with_size(3, "large", Some(5000)),
+            with_size(4, "unknown", None),
+        ];
+        let no_size = "The server didn't give the size of the mail from 'root@example.com' with \
+                       subject 'unknown', so SizeCheck can't check it";
+
+        // Without an unknown branch, a mail without a size is only logged, not taken as tiny
+        let outcome = run(&size_check(""), &mails);
+        assert_eq!(outcome.summary.passed, 1);
+        assert_eq!(outcome.summary.failed, vec!["test"]);
+        assert_eq!(
+            outcome.log,
+            [
+                vec![no_size.to_string()],
+                passed("", 1),
+                alerted_noted("", &[(&mails[0], " (100 bytes)")]),
+                deleted(1),
+            ]
+            .concat()
+        );
+
+        let outcome = run(&size_check(&format!("unknown: {ALERT},")), &mails);
+        assert_eq!(outcome.summary.passed, 1);
+        assert_eq!(outcome.summary.failed, vec!["test"; 2]);
+        assert_eq!(
+            outcome.log,
+            [
+                vec![no_size.to_string()],
+                passed("", 1),
+                alerted_noted("", &[(&mails[0], " (100 bytes)")]),
+                deleted(1),
+                alerted("", &[&mails[3]]),
+            ]
+            .concat()
+        );
+    }
+}
+
+#[cfg(test)]
+mod parts_test {
+    use super::mail_parts;
+    use imap_proto::types::{AttributeValue, Response};
+
+    // The parts of a mail with this BODYSTRUCTURE, as (type, filename, whether it's an attachment)
+    fn parts(structure: &str) -> Vec<(String, Option<String>, bool)> {
+        let response = format!("* 1 FETCH (BODYSTRUCTURE {structure})\r\n");
+        let Ok((_, Response::Fetch(_, attributes))) =
+            imap_proto::parser::parse_response(response.as_bytes())
+        else {
+            panic!("Couldn't parse {response}");
+        };
+        let Some(AttributeValue::BodyStructure(structure)) = attributes.first() else {
+            panic!("No BODYSTRUCTURE in {attributes:?}");
+        };
+
+        let mut parts = Vec::new();
+        mail_parts(structure, &mut parts);
+        parts
+            .into_iter()
+            .map(|x| (x.mime_type, x.filename, x.attachment))
+            .collect()
+    }
+
+    fn part(
+        mime_type: &str,
+        filename: Option<&str>,
+        attachment: bool,
+    ) -> (String, Option<String>, bool) {
+        (
+            mime_type.to_string(),
+            filename.map(str::to_string),
+            attachment,
+        )
+    }
+
+    #[test]
+    fn test_single_part() {
+        assert_eq!(
+            parts(r#"("TEXT" "PLAIN" ("CHARSET" "utf-8") NIL NIL "7BIT" 1000 20)"#),
+            vec![part("text/plain", None, false)]
+        );
+    }
+
+    #[test]
+    fn test_nested() {
+        let structure = concat!(
+            "(",
+            r#"(("TEXT" "PLAIN" ("CHARSET" "utf-8") NIL NIL "7BIT" 10 1 NIL NIL NIL NIL)"#,
+            r#"("TEXT" "HTML" ("CHARSET" "utf-8") NIL NIL "7BIT" 20 1 NIL NIL NIL NIL)"#,
+            r#" "ALTERNATIVE" ("BOUNDARY" "inner") NIL NIL NIL)"#,
+            r#"("APPLICATION" "PDF" NIL NIL NIL "BASE64" 100 NIL ("ATTACHMENT" ("FILENAME" "report.pdf")) NIL NIL)"#,
+            r#"("IMAGE" "PNG" NIL "<logo>" NIL "BASE64" 100 NIL ("INLINE" NIL) NIL NIL)"#,
+            r#"("IMAGE" "JPEG" NIL NIL NIL "BASE64" 100 NIL ("INLINE" ("FILENAME" "photo.jpg")) NIL NIL)"#,
+            r#"("APPLICATION" "OCTET-STREAM" ("NAME" "data.bin") NIL NIL "BASE64" 100 NIL NIL NIL NIL)"#,
+            r#"("APPLICATION" "ZIP" NIL NIL NIL "BASE64" 100 NIL ("ATTACHMENT" NIL) NIL NIL)"#,
+            r#"("MESSAGE" "RFC822" NIL NIL NIL "7BIT" 300 "#,
+            r#"("Mon, 11 Mar 2024 09:00:00 +0000" "Forwarded" NIL NIL NIL NIL NIL NIL NIL NIL) "#,
+            r#"("TEXT" "PLAIN" NIL NIL NIL "7BIT" 5 1) 10 NIL NIL NIL NIL)"#,
+            r#" "MIXED" ("BOUNDARY" "outer") NIL NIL NIL)"#,
+        );
+
+        assert_eq!(
+            parts(structure),
+            vec![
+                part("text/plain", None, false),
+                part("text/html", None, false),
+                part("application/pdf", Some("report.pdf"), true),
+                // Inline without a filename, like an image in an HTML mail
+                part("image/png", None, false),
+                part("image/jpeg", Some("photo.jpg"), true),
+                // The filename can be on the type instead
+                part("application/octet-stream", Some("data.bin"), true),
+                part("application/zip", None, true),
+                // A forwarded mail is one part, whatever's in it
+                part("message/rfc822", None, false),
+            ]
+        );
+    }
+}
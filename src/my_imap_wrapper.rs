@@ -1,9 +1,11 @@
 // Wrapping the Uid and Seq types from the imap library in non-equivalent types.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::io::{Read, Write};
 
+use imap_proto::{Response, ResponseCode, UidSetMember};
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Copy)]
 pub struct Seq(u32);
 
@@ -13,7 +15,9 @@ impl fmt::Display for Seq {
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Copy)]
+#[derive(
+    Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Copy, serde::Deserialize, serde::Serialize,
+)]
 pub struct Uid(u32);
 
 impl fmt::Display for Uid {
@@ -67,3 +71,242 @@ pub fn my_uid_search<T: Read + Write>(
         Err(x) => Err(x),
     }
 }
+
+// Turns on an extension with ENABLE (RFC 5161), returning whether the server says it did.
+pub fn my_enable<T: Read + Write>(
+    session: &mut imap::Session<T>,
+    extension: &str,
+) -> imap::error::Result<bool> {
+    let (lines, _) = session.run(format!("ENABLE {extension}"))?;
+
+    Ok(is_enabled(&lines, extension))
+}
+
+fn is_enabled(lines: &[u8], extension: &str) -> bool {
+    String::from_utf8_lossy(lines).lines().any(|line| {
+        let mut words = line.split_whitespace();
+        words.next() == Some("*")
+            && words
+                .next()
+                .is_some_and(|x| x.eq_ignore_ascii_case("ENABLED"))
+            && words.any(|x| x.eq_ignore_ascii_case(extension))
+    })
+}
+
+// With QRESYNC enabled, the UIDs of the mails in the selected mailbox that have appeared or changed
+// since the given HIGHESTMODSEQ, and of those that have gone, as (changed, vanished).
+pub fn my_uid_changed_since<T: Read + Write>(
+    session: &mut imap::Session<T>,
+    mod_seq: u64,
+) -> imap::error::Result<(HashSet<Uid>, HashSet<Uid>)> {
+    // The VANISHED response comes through the unsolicited responses, so clear out anything
+    // older first
+    while session.unsolicited_responses.try_recv().is_ok() {}
+
+    let changed = session
+        .uid_fetch("1:*", format!("(UID) (CHANGEDSINCE {mod_seq} VANISHED)"))?
+        .iter()
+        .filter_map(|x| x.uid.map(Uid::from))
+        .collect();
+
+    let mut vanished = HashSet::new();
+    while let Ok(response) = session.unsolicited_responses.try_recv() {
+        if let imap::types::UnsolicitedResponse::Vanished { uids, .. } = response {
+            vanished.extend(uids.into_iter().flatten().map(Uid::from));
+        }
+    }
+
+    Ok((changed, vanished))
+}
+
+// Quotes a mailbox name the same way the imap library does internally; it doesn't make that
+// available to us.
+fn quote_mailbox(mailbox_name: &str) -> imap::error::Result<String> {
+    if mailbox_name.contains(['\r', '\n']) {
+        return Err(imap::error::Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Mailbox name {mailbox_name:?} contains a line break"),
+        )));
+    }
+
+    Ok(format!(
+        "\"{}\"",
+        mailbox_name.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+// Like imap's uid_mv, except that it also returns the mapping from the old UIDs to the UIDs in
+// the destination mailbox, if the server gives us one (via COPYUID, from the UIDPLUS extension).
+// If it doesn't, the mapping is empty.
+pub fn my_uid_mv<T: Read + Write>(
+    session: &mut imap::Session<T>,
+    uid_set: impl AsRef<str>,
+    mailbox_name: impl AsRef<str>,
+) -> imap::error::Result<HashMap<Uid, Uid>> {
+    let (lines, _) = session.run(format!(
+        "UID MOVE {} {}",
+        uid_set.as_ref(),
+        quote_mailbox(mailbox_name.as_ref())?
+    ))?;
+
+    Ok(parse_copyuid(&lines))
+}
+
+fn parse_copyuid(mut lines: &[u8]) -> HashMap<Uid, Uid> {
+    let mut mapping = HashMap::new();
+
+    // For MOVE the COPYUID comes in an untagged OK, for COPY it's on the tagged one
+    while let Ok((rest, response)) = imap_proto::parser::parse_response(lines) {
+        if let Response::Data {
+            code: Some(ResponseCode::CopyUid(_, from, to)),
+            ..
+        }
+        | Response::Done {
+            code: Some(ResponseCode::CopyUid(_, from, to)),
+            ..
+        } = response
+        {
+            mapping.extend(
+                expand_uid_set(&from)
+                    .map(Uid::from)
+                    .zip(expand_uid_set(&to).map(Uid::from)),
+            );
+        }
+        lines = rest;
+    }
+
+    mapping
+}
+
+fn expand_uid_set(set: &[UidSetMember]) -> impl Iterator<Item = u32> + '_ {
+    set.iter().flat_map(|member| match member {
+        UidSetMember::Uid(uid) => *uid..=*uid,
+        UidSetMember::UidRange(range) => range.clone(),
+    })
+}
+
+/// The UIDs as an IMAP sequence set, with runs collapsed into ranges, e.g. "1:500,502".
+pub fn uid_set(uids: &[Uid]) -> String {
+    let mut sorted = uids.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for Uid(uid) in sorted {
+        match ranges.last_mut() {
+            Some((_, last)) if last.checked_add(1) == Some(uid) => *last = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{first}:{last}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The UIDs split into chunks of at most `chunk_size` each (in UID order), so that no one command
+/// gets longer than servers are willing to take.
+pub fn uid_chunks(uids: &[Uid], chunk_size: usize) -> Vec<Vec<Uid>> {
+    let mut sorted = uids.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    sorted
+        .chunks(chunk_size.max(1))
+        .map(<[Uid]>::to_vec)
+        .collect()
+}
+
+#[cfg(test)]
+mod uid_set_test {
+    use super::{uid_chunks, uid_set, Uid};
+
+    fn uids(uids: &[u32]) -> Vec<Uid> {
+        uids.iter().copied().map(Uid::from).collect()
+    }
+
+    #[test]
+    fn test_uid_set() {
+        assert_eq!(uid_set(&uids(&[])), "");
+        assert_eq!(uid_set(&uids(&[7])), "7");
+        assert_eq!(uid_set(&uids(&[1, 2, 3, 5, 7, 8])), "1:3,5,7:8");
+        // Order and repeats don't matter
+        assert_eq!(uid_set(&uids(&[502, 3, 2, 1, 2, 4])), "1:4,502");
+
+        let many: Vec<u32> = (1..=500).chain([502]).collect();
+        assert_eq!(uid_set(&uids(&many)), "1:500,502");
+
+        // The last possible UID doesn't wrap round to join up with the first
+        assert_eq!(
+            uid_set(&uids(&[0, u32::MAX - 1, u32::MAX])),
+            "0,4294967294:4294967295"
+        );
+        assert_eq!(uid_set(&uids(&[1, u32::MAX])), "1,4294967295");
+    }
+
+    #[test]
+    fn test_uid_chunks() {
+        let chunks = uid_chunks(&uids(&[9, 1, 2, 3, 5, 6, 8, 2]), 3);
+        assert_eq!(
+            chunks.iter().map(|x| uid_set(x)).collect::<Vec<_>>(),
+            vec!["1:3", "5:6,8", "9"]
+        );
+        assert_eq!(uid_chunks(&uids(&[1, 2]), 0).len(), 2);
+        assert!(uid_chunks(&uids(&[]), 10).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod enable_test {
+    use super::is_enabled;
+
+    #[test]
+    fn test_is_enabled() {
+        assert!(is_enabled(
+            b"* ENABLED QRESYNC\r\na2 OK Enabled.\r\n",
+            "QRESYNC"
+        ));
+        assert!(is_enabled(
+            b"* enabled CONDSTORE qresync\r\na2 OK Enabled.\r\n",
+            "QRESYNC"
+        ));
+        assert!(!is_enabled(b"* ENABLED\r\na2 OK Enabled.\r\n", "QRESYNC"));
+        assert!(!is_enabled(b"a2 BAD Unknown command.\r\n", "QRESYNC"));
+    }
+}
+
+#[cfg(test)]
+mod copyuid_test {
+    use super::{parse_copyuid, Uid};
+
+    #[test]
+    fn test_parse_copyuid() {
+        let lines = b"* OK [COPYUID 1702449991 3:4,7 10:12] Moved UIDs.\r\n\
+                      * 2 EXPUNGE\r\n\
+                      * 2 EXPUNGE\r\n\
+                      * 4 EXPUNGE\r\n\
+                      a3 OK Move completed.\r\n";
+
+        let mapping = parse_copyuid(lines);
+
+        assert_eq!(mapping.len(), 3);
+        assert_eq!(mapping[&Uid::from(3)], Uid::from(10));
+        assert_eq!(mapping[&Uid::from(4)], Uid::from(11));
+        assert_eq!(mapping[&Uid::from(7)], Uid::from(12));
+    }
+
+    #[test]
+    fn test_parse_copyuid_without_uidplus() {
+        let lines = b"* 2 EXPUNGE\r\na3 OK Move completed.\r\n";
+
+        assert!(parse_copyuid(lines).is_empty());
+    }
+}
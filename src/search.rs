@@ -0,0 +1,225 @@
+// Building IMAP SEARCH criteria, so that whatever strings the config file has in it end up in the
+// command as exactly those strings, rather than being pasted in between quotes.
+
+use thiserror::Error;
+
+use crate::my_imap_wrapper::{uid_set, Uid};
+
+/// Search criteria, which `to_query` turns into what goes after `UID SEARCH`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Search {
+    All,
+    Uids(Vec<Uid>),
+    // On or after the start of this day, by the server's idea of the mail's internal date
+    Since(time::Date),
+    Body(String),
+    // All of them
+    And(Vec<Search>),
+    Or(Box<Search>, Box<Search>),
+}
+
+/// Which of the extensions that let a client send literals without waiting for the server to ask
+/// for them (RFC 7888) the server has; we need them for strings that can't be quoted, since the
+/// imap library only sends single lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Literals {
+    Unsupported,
+    // LITERAL-, which only allows small ones
+    UpTo4096,
+    // LITERAL+
+    Any,
+}
+
+impl Literals {
+    /// From the server's CAPABILITY response.
+    pub fn from_capabilities(capabilities: &imap::types::Capabilities) -> Literals {
+        if capabilities.has_str("LITERAL+") {
+            Literals::Any
+        } else if capabilities.has_str("LITERAL-") {
+            Literals::UpTo4096
+        } else {
+            Literals::Unsupported
+        }
+    }
+
+    fn allow(self, length: usize) -> bool {
+        match self {
+            Literals::Unsupported => false,
+            Literals::UpTo4096 => length <= 4096,
+            Literals::Any => true,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SearchError {
+    #[error("can't search for {0:?}; IMAP searches can't contain line breaks or NULs")]
+    Unsendable(String),
+    #[error("can't search for {0:?}; searching for text that isn't ASCII needs the server to support LITERAL+ (or LITERAL-, for short text), and it doesn't")]
+    NeedsLiterals(String),
+}
+
+impl Search {
+    /// Mails whose bodies contain any of the strings.  Like IMAP itself, no strings at all
+    /// matches everything.
+    pub fn any_body(strings: &[String]) -> Search {
+        strings
+            .iter()
+            .map(|x| Search::Body(x.clone()))
+            .reduce(|acc, x| Search::Or(Box::new(x), Box::new(acc)))
+            .unwrap_or(Search::All)
+    }
+
+    /// Mails whose bodies contain all of the strings.
+    pub fn all_body(strings: &[String]) -> Search {
+        Search::And(strings.iter().map(|x| Search::Body(x.clone())).collect())
+    }
+
+    /// The criteria as they go after `UID SEARCH`, with `CHARSET UTF-8` in front if any of the
+    /// strings need it.
+    pub fn to_query(&self, literals: Literals) -> Result<String, SearchError> {
+        let mut query = String::new();
+        self.write(&mut query, literals)?;
+
+        if self.needs_utf8() {
+            query.insert_str(0, "CHARSET UTF-8 ");
+        }
+        Ok(query)
+    }
+
+    fn needs_utf8(&self) -> bool {
+        match self {
+            Search::All | Search::Uids(_) | Search::Since(_) => false,
+            Search::Body(text) => !text.is_ascii(),
+            Search::And(all) => all.iter().any(Search::needs_utf8),
+            Search::Or(left, right) => left.needs_utf8() || right.needs_utf8(),
+        }
+    }
+
+    fn write(&self, query: &mut String, literals: Literals) -> Result<(), SearchError> {
+        match self {
+            Search::All => query.push_str("ALL"),
+            Search::Uids(uids) => {
+                query.push_str("UID ");
+                query.push_str(&uid_set(uids));
+            }
+            Search::Since(date) => {
+                let format =
+                    time::macros::format_description!("SINCE [day]-[month repr:short]-[year]");
+                query.push_str(&date.format(&format).expect("Couldn't format SINCE date"));
+            }
+            Search::Body(text) => {
+                query.push_str("BODY ");
+                query.push_str(&string(text, literals)?);
+            }
+            Search::And(all) if all.is_empty() => query.push_str("ALL"),
+            Search::And(all) => {
+                for (index, search) in all.iter().enumerate() {
+                    if index > 0 {
+                        query.push(' ');
+                    }
+                    search.write(query, literals)?;
+                }
+            }
+            Search::Or(left, right) => {
+                query.push_str("OR (");
+                left.write(query, literals)?;
+                query.push_str(") (");
+                right.write(query, literals)?;
+                query.push(')');
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A string as a quoted string if it can be one, and a non-synchronizing literal if not.
+fn string(text: &str, literals: Literals) -> Result<String, SearchError> {
+    if text.contains(['\r', '\n', '\0']) {
+        return Err(SearchError::Unsendable(text.to_string()));
+    }
+
+    if text.is_ascii() {
+        Ok(format!(
+            "\"{}\"",
+            text.replace('\\', "\\\\").replace('"', "\\\"")
+        ))
+    } else if literals.allow(text.len()) {
+        Ok(format!("{{{}+}}\r\n{text}", text.len()))
+    } else {
+        Err(SearchError::NeedsLiterals(text.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod search_test {
+    use super::{Literals, Search, SearchError};
+    use crate::my_imap_wrapper::Uid;
+    use time::macros::date;
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|x| (*x).to_string()).collect()
+    }
+
+    #[test]
+    fn test_quoting() {
+        let query = |text: &str| Search::Body(text.to_string()).to_query(Literals::Unsupported);
+
+        assert_eq!(query("plain"), Ok(r#"BODY "plain""#.to_string()));
+        assert_eq!(query(r#"say "hi""#), Ok(r#"BODY "say \"hi\"""#.to_string()));
+        assert_eq!(query(r"C:\temp\"), Ok(r#"BODY "C:\\temp\\""#.to_string()));
+        assert_eq!(query(""), Ok(r#"BODY """#.to_string()));
+        // Would otherwise close the group early
+        assert_eq!(query(") ALL ("), Ok(r#"BODY ") ALL (""#.to_string()));
+
+        for bad in ["two\nlines", "two\r\nlines", "nul\0"] {
+            assert_eq!(query(bad), Err(SearchError::Unsendable(bad.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_utf8() {
+        let search = Search::And(vec![
+            Search::Uids(vec![Uid::from(3), Uid::from(5)]),
+            Search::Body("Größe".to_string()),
+        ]);
+
+        assert_eq!(
+            search.to_query(Literals::Any),
+            Ok("CHARSET UTF-8 UID 3,5 BODY {7+}\r\nGröße".to_string())
+        );
+        assert_eq!(
+            search.to_query(Literals::UpTo4096),
+            search.to_query(Literals::Any)
+        );
+        assert_eq!(
+            search.to_query(Literals::Unsupported),
+            Err(SearchError::NeedsLiterals("Größe".to_string()))
+        );
+
+        let huge = Search::Body("é".repeat(3000));
+        assert!(huge.to_query(Literals::UpTo4096).is_err());
+        assert!(huge.to_query(Literals::Any).is_ok());
+    }
+
+    #[test]
+    fn test_combinations() {
+        assert_eq!(
+            Search::any_body(&strings(&["a", "b", "c"])).to_query(Literals::Unsupported),
+            Ok(r#"OR (BODY "c") (OR (BODY "b") (BODY "a"))"#.to_string())
+        );
+        assert_eq!(
+            Search::all_body(&strings(&["a", "b"])).to_query(Literals::Unsupported),
+            Ok(r#"BODY "a" BODY "b""#.to_string())
+        );
+        assert_eq!(
+            Search::any_body(&[]).to_query(Literals::Unsupported),
+            Ok("ALL".to_string())
+        );
+        assert_eq!(
+            Search::Since(date!(2023 - 09 - 02)).to_query(Literals::Unsupported),
+            Ok("SINCE 02-Sep-2023".to_string())
+        );
+    }
+}
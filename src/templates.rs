@@ -0,0 +1,337 @@
+// Reusable pieces of config: named filter sets and checker trees, which handlers pull in with
+// `{ Ref: "name" }` or `{ Ref: { name: "name", params: { ... } } }`.
+//
+// This all happens on the untyped config, before it's deserialized into `Settings`, since a
+// parameter can stand in for anything (a number of days, a whole subtree, part of a regex).
+
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+type Params = Map<String, Value>;
+
+struct Templates {
+    filter_sets: HashMap<String, Value>,
+    trees: HashMap<String, Value>,
+}
+
+/// Removes the top-level `filter_sets` and `trees` from the given config, and replaces every
+/// `Ref` to them with (a copy of) what it refers to.
+///
+/// A `Ref` that's an element of a list refers to a filter set, and is replaced by all of that
+/// filter set's filters; anywhere else, it refers to a tree.  Inside a filter set or tree, a
+/// string that's just `"${name}"` is replaced by that parameter's value, whatever type it is, and
+/// `${name}` anywhere else in a string is replaced by the parameter's text.
+pub fn expand(mut settings: Value) -> Result<Value, config::ConfigError> {
+    let Some(map) = settings.as_object_mut() else {
+        return Ok(settings);
+    };
+
+    let templates = Templates {
+        filter_sets: take_map(map, "filter_sets")?,
+        trees: take_map(map, "trees")?,
+    };
+
+    templates.expand_value(settings, None, &mut Vec::new())
+}
+
+fn take_map(
+    settings: &mut Map<String, Value>,
+    key: &str,
+) -> Result<HashMap<String, Value>, config::ConfigError> {
+    match settings.remove(key) {
+        None => Ok(HashMap::new()),
+        Some(Value::Object(map)) => Ok(map.into_iter().collect()),
+        Some(_) => Err(config::ConfigError::Message(format!(
+            "{key} must be a map from names to definitions"
+        ))),
+    }
+}
+
+impl Templates {
+    // `params` is None outside of templates, where there's nothing to substitute; `stack` is the
+    // templates we're currently inside of, to catch them referring to each other in a loop.
+    fn expand_value(
+        &self,
+        value: Value,
+        params: Option<&Params>,
+        stack: &mut Vec<String>,
+    ) -> Result<Value, config::ConfigError> {
+        match value {
+            Value::Object(map) => {
+                if let Some(target) = ref_target(&map) {
+                    let (name, ref_params) = self.parse_ref(target, params, stack)?;
+                    let Some(tree) = self.trees.get(&name) else {
+                        let hint = if self.filter_sets.contains_key(&name) {
+                            "; there's a filter set by that name, but those can only be used inside a list of filters"
+                        } else {
+                            ""
+                        };
+                        return Err(config::ConfigError::Message(format!(
+                            "Reference to unknown tree '{name}'{hint}"
+                        )));
+                    };
+                    return self.expand_template(&name, tree, &ref_params, stack);
+                }
+
+                let mut expanded = Map::new();
+                for (key, value) in map {
+                    expanded.insert(key, self.expand_value(value, params, stack)?);
+                }
+                Ok(Value::Object(expanded))
+            }
+            Value::Array(values) => {
+                let mut expanded = Vec::new();
+                for value in values {
+                    let target = value.as_object().and_then(ref_target);
+                    let Some(target) = target else {
+                        expanded.push(self.expand_value(value, params, stack)?);
+                        continue;
+                    };
+
+                    let (name, ref_params) = self.parse_ref(target, params, stack)?;
+                    let Some(filter_set) = self.filter_sets.get(&name) else {
+                        return Err(config::ConfigError::Message(format!(
+                            "Reference to unknown filter set '{name}'"
+                        )));
+                    };
+                    match self.expand_template(&name, filter_set, &ref_params, stack)? {
+                        Value::Array(filters) => expanded.extend(filters),
+                        _ => {
+                            return Err(config::ConfigError::Message(format!(
+                                "Filter set '{name}' must be a list of filters"
+                            )))
+                        }
+                    }
+                }
+                Ok(Value::Array(expanded))
+            }
+            Value::String(string) => match params {
+                Some(params) => substitute(&string, params, stack),
+                None => Ok(Value::String(string)),
+            },
+            _ => Ok(value),
+        }
+    }
+
+    fn expand_template(
+        &self,
+        name: &str,
+        template: &Value,
+        params: &Params,
+        stack: &mut Vec<String>,
+    ) -> Result<Value, config::ConfigError> {
+        if stack.iter().any(|x| x == name) {
+            return Err(config::ConfigError::Message(format!(
+                "Template '{name}' refers to itself: {} -> {name}",
+                stack.join(" -> ")
+            )));
+        }
+
+        stack.push(name.to_string());
+        let expanded = self.expand_value(template.clone(), Some(params), stack)?;
+        stack.pop();
+
+        Ok(expanded)
+    }
+
+    // Splits a Ref's target into the name of the template and its parameters; the parameters can
+    // themselves use the parameters of the template the Ref is in.
+    fn parse_ref(
+        &self,
+        target: &Value,
+        params: Option<&Params>,
+        stack: &mut Vec<String>,
+    ) -> Result<(String, Params), config::ConfigError> {
+        let invalid = || {
+            config::ConfigError::Message(format!(
+                "Ref must be either a name or {{ name: ..., params: {{ ... }} }}, not {target}"
+            ))
+        };
+
+        match target {
+            Value::String(name) => Ok((name.clone(), Params::new())),
+            Value::Object(map) => {
+                let name = map
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .ok_or_else(invalid)?;
+                if map.keys().any(|x| x != "name" && x != "params") {
+                    return Err(invalid());
+                }
+                let ref_params = match map.get("params") {
+                    None => Params::new(),
+                    Some(Value::Object(ref_params)) => {
+                        let mut expanded = Params::new();
+                        for (key, value) in ref_params {
+                            expanded.insert(
+                                key.clone(),
+                                self.expand_value(value.clone(), params, stack)?,
+                            );
+                        }
+                        expanded
+                    }
+                    Some(_) => return Err(invalid()),
+                };
+                Ok((name.to_string(), ref_params))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// If this is a `{ Ref: ... }`, what it refers to
+fn ref_target(map: &Map<String, Value>) -> Option<&Value> {
+    if map.len() == 1 {
+        map.get("Ref")
+    } else {
+        None
+    }
+}
+
+fn substitute(
+    string: &str,
+    params: &Params,
+    stack: &[String],
+) -> Result<Value, config::ConfigError> {
+    let lookup = |name: &str| {
+        params.get(name).ok_or_else(|| {
+            config::ConfigError::Message(format!(
+                "Template '{}' uses parameter '{name}', which wasn't given",
+                stack.last().map_or("", String::as_str)
+            ))
+        })
+    };
+
+    // A placeholder on its own keeps the parameter's type, so `days: "${days}"` gets a number
+    if let Some(name) = string
+        .strip_prefix("${")
+        .and_then(|x| x.strip_suffix('}'))
+        .filter(|x| !x.contains('}'))
+    {
+        return lookup(name).cloned();
+    }
+
+    let mut result = String::new();
+    let mut rest = string;
+    while let Some((before, after)) = rest.split_once("${") {
+        let Some((name, after)) = after.split_once('}') else {
+            break;
+        };
+        result.push_str(before);
+        match lookup(name)? {
+            Value::String(value) => result.push_str(value),
+            value @ (Value::Number(_) | Value::Bool(_)) => result.push_str(&value.to_string()),
+            _ => {
+                return Err(config::ConfigError::Message(format!(
+                    "Parameter '{name}' is used inside a string, so it must be a string, number or boolean"
+                )))
+            }
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+
+    Ok(Value::String(result))
+}
+
+#[cfg(test)]
+mod templates_test {
+    use super::expand;
+    use serde_json::json;
+
+    #[test]
+    fn test_expand() {
+        let settings = json!({
+            "filter_sets": {
+                "cron": [ { "Match": { "Subject": "^Cron ${host}" } } ],
+                "puppet": [
+                    { "Ref": { "name": "cron", "params": { "host": "${host}" } } },
+                    { "Match": { "Subject": "puppet agent" } },
+                ],
+            },
+            "trees": {
+                "daily": {
+                    "DateCheck": {
+                        "days": "${days}",
+                        "empty_ok": "YoungerThan",
+                        "older_than": { "Action": "Delete" },
+                        "younger_than": "${younger}",
+                    },
+                },
+            },
+            "handlers": [
+                {
+                    "name": "puppet",
+                    "filters": [
+                        { "Match": { "From": "root" } },
+                        { "Ref": { "name": "puppet", "params": { "host": "web1" } } },
+                    ],
+                    "checker_tree": {
+                        "Ref": {
+                            "name": "daily",
+                            "params": { "days": 2, "younger": { "Action": "Success" } },
+                        },
+                    },
+                },
+            ],
+        });
+
+        assert_eq!(
+            expand(settings).unwrap(),
+            json!({
+                "handlers": [
+                    {
+                        "name": "puppet",
+                        "filters": [
+                            { "Match": { "From": "root" } },
+                            { "Match": { "Subject": "^Cron web1" } },
+                            { "Match": { "Subject": "puppet agent" } },
+                        ],
+                        "checker_tree": {
+                            "DateCheck": {
+                                "days": 2,
+                                "empty_ok": "YoungerThan",
+                                "older_than": { "Action": "Delete" },
+                                "younger_than": { "Action": "Success" },
+                            },
+                        },
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let cycle = json!({
+            "trees": {
+                "a": { "MatchCheck": { "matched": { "Ref": "b" } } },
+                "b": { "Ref": "a" },
+            },
+            "handlers": [ { "checker_tree": { "Ref": "a" } } ],
+        });
+        assert!(expand(cycle)
+            .unwrap_err()
+            .to_string()
+            .contains("a -> b -> a"));
+
+        let missing = json!({
+            "trees": { "a": { "DateCheck": { "days": "${days}" } } },
+            "handlers": [ { "checker_tree": { "Ref": "a" } } ],
+        });
+        assert!(expand(missing)
+            .unwrap_err()
+            .to_string()
+            .contains("parameter 'days'"));
+
+        let misplaced = json!({
+            "filter_sets": { "a": [] },
+            "handlers": [ { "filters": { "Ref": "a" } } ],
+        });
+        assert!(expand(misplaced)
+            .unwrap_err()
+            .to_string()
+            .contains("unknown tree 'a'"));
+    }
+}
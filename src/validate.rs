@@ -0,0 +1,743 @@
+// Support for `amcheck validate`: finding mistakes in the config file before a real run trips
+// over them.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::configuration::{
+    Account, AccountConfig, BodySearch, CheckerTree, DateEmpty, Filter, Handler, MatchEmpty,
+    MatcherPart, Selection,
+};
+use crate::includes::included_files;
+use crate::search::{Literals, Search};
+
+// Just the parts of the config file that have any depth to them.  The config crate throws away
+// where in the file a deserialization error happened, so we run these through json5 ourselves to
+// get that back; everything else is simple enough that the config crate's error is enough.
+#[derive(serde::Deserialize)]
+#[allow(dead_code)] // We only want the errors from deserializing it
+struct HandlerLayout {
+    #[serde(default)]
+    handlers: Vec<Handler>,
+    #[serde(default)]
+    accounts: Vec<AccountConfig>,
+    #[serde(default)]
+    handler_sets: HashMap<String, Vec<Handler>>,
+}
+
+/// Parses the config file, and any files it includes, with json5 directly, and on failure returns
+/// the error prefixed with the file name and the line and column it happened at, compiler-style.
+/// Files that aren't json5 (or JSON) are left to get_configuration.
+pub fn locate_error(path: &Path) -> Result<(), String> {
+    locate_error_in(path, &mut HashSet::new())
+}
+
+fn locate_error_in(path: &Path, visited: &mut HashSet<PathBuf>) -> Result<(), String> {
+    // Include loops are get_configuration's problem; we just don't want to go round forever
+    if !visited.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf())) {
+        return Ok(());
+    }
+
+    if !matches!(
+        path.extension().and_then(std::ffi::OsStr::to_str),
+        Some("json5" | "json")
+    ) {
+        return Ok(());
+    }
+
+    let text = std::fs::read_to_string(path).map_err(|x| format!("{}: {x}", path.display()))?;
+    let value = json5::from_str::<Value>(&text).map_err(|e| describe(path, e))?;
+
+    // Handlers that use templates aren't valid handlers until the templates are expanded, by
+    // which point there's no telling where anything came from, so those we leave to
+    // get_configuration
+    if !uses_templates(&value) {
+        json5::from_str::<HandlerLayout>(&text).map_err(|e| describe(path, e))?;
+    }
+
+    for included in included_files(&value, path).map_err(|x| x.to_string())? {
+        locate_error_in(&included, visited)?;
+    }
+
+    Ok(())
+}
+
+fn uses_templates(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map.contains_key("Ref") || map.values().any(uses_templates),
+        Value::Array(values) => values.iter().any(uses_templates),
+        _ => false,
+    }
+}
+
+fn describe(path: &Path, error: json5::Error) -> String {
+    match error {
+        json5::Error::Message {
+            msg,
+            location: Some(location),
+        } => {
+            // Syntax errors come from pest, which puts the location on a line of its own, and
+            // then draws a picture of where the problem is with the actual complaint at the
+            // bottom; put the complaint first, like everything else
+            let msg = match msg.trim_start().strip_prefix("--> ") {
+                Some(rest) => {
+                    let picture = rest.split_once('\n').map_or("", |x| x.1);
+                    match picture.rsplit_once("= ") {
+                        Some((picture, complaint)) => {
+                            format!("{complaint}\n{}", picture.trim_end())
+                        }
+                        None => picture.to_string(),
+                    }
+                }
+                None => msg,
+            };
+
+            format!(
+                "{}:{}:{}: {msg}",
+                path.display(),
+                location.line,
+                location.column,
+            )
+        }
+        json5::Error::Message {
+            msg,
+            location: None,
+        } => format!("{}: {msg}", path.display()),
+    }
+}
+
+/// Finds things in the given account's handlers that are allowed, but almost certainly not what
+/// was meant.  Each problem is returned as a human-readable description.
+pub fn lint(account: &Account) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (index, handler) in account.handlers.iter().enumerate() {
+        let earlier = &account.handlers[..index];
+
+        if earlier.iter().any(|x| x.name == handler.name) {
+            problems.push(format!(
+                "Account '{}': handler name '{}' is used more than once",
+                account.name, handler.name
+            ));
+        }
+
+        if let Some(shadow) = earlier.iter().find(|x| shadows(account, x, handler)) {
+            problems.push(format!(
+                "Account '{}': handler '{}' will never see any mail in '{}', because every mail its filters match is moved to '{}' by the earlier handler '{}'",
+                account.name,
+                handler.name,
+                account.handler_storage_folder(handler),
+                account.handler_storage_folder(shadow),
+                shadow.name
+            ));
+        }
+
+        lint_tree(
+            &mut problems,
+            &format!("Account '{}': handler '{}'", account.name, handler.name),
+            "checker_tree",
+            &handler.checker_tree,
+        );
+    }
+
+    problems
+}
+
+// Whether, in the move phase, the earlier handler matches every mail that the later handler
+// would, in every folder the later handler looks at, and moves it somewhere the later handler
+// won't see it.  (Several handlers running different checks on the same mail in the same storage
+// folder is perfectly normal.)  Filters are all anded together, so if the earlier handler's
+// filters are a subset of the later one's, anything the later one matches, the earlier one does
+// too.  We can only compare regexes as strings, so this misses some cases, but it doesn't have
+// false positives.
+fn shadows(account: &Account, earlier: &Handler, later: &Handler) -> bool {
+    // Empty filter lists don't match anything in the move phase
+    if earlier.filters.is_empty()
+        || later.filters.is_empty()
+        || account.handler_storage_folder(earlier) == account.handler_storage_folder(later)
+    {
+        return false;
+    }
+
+    let later_folders = if later.source_folders.is_empty() {
+        &account.source_folders
+    } else {
+        &later.source_folders
+    };
+
+    earlier
+        .filters
+        .iter()
+        .all(|x| later.filters.iter().any(|y| same_filter(x, y)))
+        && later_folders
+            .iter()
+            .all(|x| account.handler_watches(earlier, x))
+}
+
+fn same_filter(a: &Filter, b: &Filter) -> bool {
+    let same_part = |a: &MatcherPart, b: &MatcherPart| match (a, b) {
+        (MatcherPart::Subject(a), MatcherPart::Subject(b))
+        | (MatcherPart::From(a), MatcherPart::From(b)) => a.as_str() == b.as_str(),
+        _ => false,
+    };
+
+    match (a, b) {
+        (Filter::Match(a), Filter::Match(b)) | (Filter::UnMatch(a), Filter::UnMatch(b)) => {
+            same_part(a, b)
+        }
+        _ => false,
+    }
+}
+
+// Strings that no server could search for, whatever it supports, and strings that only some can
+fn lint_search_strings(
+    problems: &mut Vec<String>,
+    owner: &str,
+    path: &str,
+    node: &str,
+    strings: &[String],
+) {
+    if let Err(error) = Search::all_body(strings).to_query(Literals::Any) {
+        problems.push(format!("{owner}: the {node} at {path} {error}"));
+    } else if let Some(string) = strings.iter().find(|x| !x.is_ascii()) {
+        problems.push(format!("{owner}: the {node} at {path} searches the server for {string:?}, which isn't ASCII; on servers without LITERAL+ or LITERAL- that falls back to fetching every mail and searching it here, so use a Substring search to make that explicit, or stick to ASCII"));
+    }
+}
+
+// Likewise, for the strings in a selection (which are always searched for on the server)
+fn lint_selection(
+    problems: &mut Vec<String>,
+    owner: &str,
+    path: &str,
+    node: &str,
+    selection: &Selection,
+) {
+    if let Selection::BodyAny(strings) | Selection::BodyAll(strings) = selection {
+        lint_search_strings(problems, owner, path, node, strings);
+    }
+}
+
+fn lint_tree(problems: &mut Vec<String>, owner: &str, path: &str, tree: &CheckerTree) {
+    // Every node type with an `empty_ok`, as (node name, branch names, the branch empty_ok picks,
+    // the two branches)
+    let (node, names, empty_branch, branches) = match tree {
+        CheckerTree::Stop | CheckerTree::Action(_) => return,
+        CheckerTree::CountCheck(check) => {
+            for (name, branch) in [
+                ("greater_than", &check.greater_than),
+                ("less_than", &check.less_than),
+                ("equal", &check.equal),
+            ] {
+                lint_tree(
+                    problems,
+                    owner,
+                    &format!("{path}.CountCheck.{name}"),
+                    branch,
+                );
+            }
+            return;
+        }
+        CheckerTree::CountRangeCheck(check) => {
+            if check.max.is_some_and(|x| x < check.min) {
+                problems.push(format!("{owner}: the CountRangeCheck at {path} has a max less than its min, so nothing is ever in range"));
+            }
+            for (name, branch) in [
+                ("below", &check.below),
+                ("in_range", &check.in_range),
+                ("above", &check.above),
+            ] {
+                lint_tree(
+                    problems,
+                    owner,
+                    &format!("{path}.CountRangeCheck.{name}"),
+                    branch,
+                );
+            }
+            return;
+        }
+        CheckerTree::RatioCheck(check) => {
+            if check.max_percent.is_some_and(|x| x < check.min_percent) {
+                problems.push(format!("{owner}: the RatioCheck at {path} has a max_percent less than its min_percent, so nothing is ever in range"));
+            }
+            for selection in std::iter::once(&check.numerator).chain(&check.denominator) {
+                lint_selection(problems, owner, path, "RatioCheck", selection);
+            }
+            for (name, branch) in [
+                ("below", &check.below),
+                ("in_range", &check.in_range),
+                ("above", &check.above),
+            ] {
+                lint_tree(
+                    problems,
+                    owner,
+                    &format!("{path}.RatioCheck.{name}"),
+                    branch,
+                );
+            }
+            return;
+        }
+        CheckerTree::AggregateCheck(check) => {
+            for (name, branch) in [
+                ("matched", &check.matched),
+                ("not_matched", &check.not_matched),
+                ("not_found", &check.not_found),
+            ] {
+                lint_tree(
+                    problems,
+                    owner,
+                    &format!("{path}.AggregateCheck.{name}"),
+                    branch,
+                );
+            }
+            return;
+        }
+        CheckerTree::GroupBy(check) => {
+            lint_tree(
+                problems,
+                owner,
+                &format!("{path}.GroupBy.subtree"),
+                &check.subtree,
+            );
+            if let Some(no_key) = &check.no_key {
+                lint_tree(problems, owner, &format!("{path}.GroupBy.no_key"), no_key);
+            }
+            return;
+        }
+        CheckerTree::Correlate(check) => {
+            for selection in [&check.start, &check.finish] {
+                lint_selection(problems, owner, path, "Correlate", selection);
+            }
+            for (name, branch) in [
+                ("paired", Some(&check.paired)),
+                ("timed_out", Some(&check.timed_out)),
+                ("pending", check.pending.as_ref()),
+                ("other", check.other.as_ref()),
+            ] {
+                if let Some(branch) = branch {
+                    lint_tree(problems, owner, &format!("{path}.Correlate.{name}"), branch);
+                }
+            }
+            return;
+        }
+        CheckerTree::Latest(check) => {
+            if check.n == 0 {
+                problems.push(format!(
+                    "{owner}: the Latest at {path} has n set to 0, so every mail goes down rest"
+                ));
+            }
+            for (name, branch) in [("latest", &check.latest), ("rest", &check.rest)] {
+                lint_tree(problems, owner, &format!("{path}.Latest.{name}"), branch);
+            }
+            return;
+        }
+        CheckerTree::DuplicateCheck(check) => {
+            for (name, branch) in [
+                ("originals", &check.originals),
+                ("duplicates", &check.duplicates),
+            ] {
+                lint_tree(
+                    problems,
+                    owner,
+                    &format!("{path}.DuplicateCheck.{name}"),
+                    branch,
+                );
+            }
+            return;
+        }
+        CheckerTree::SizeCheck(check) => {
+            if check.max_bytes.is_some_and(|x| x < check.min_bytes) {
+                problems.push(format!("{owner}: the SizeCheck at {path} has a max_bytes less than its min_bytes, so nothing is ever in range"));
+            }
+            for (name, branch) in [
+                ("below", Some(&check.below)),
+                ("in_range", Some(&check.in_range)),
+                ("above", Some(&check.above)),
+                ("unknown", check.unknown.as_ref()),
+            ] {
+                if let Some(branch) = branch {
+                    lint_tree(problems, owner, &format!("{path}.SizeCheck.{name}"), branch);
+                }
+            }
+            return;
+        }
+        CheckerTree::ExpectSchedule(check) => {
+            if check.lookback.0 <= check.grace.0 {
+                problems.push(format!("{owner}: the ExpectSchedule at {path} has a lookback no longer than its grace, so it can never find a missed run"));
+            }
+            for (name, branch) in [
+                ("missing", &check.missing),
+                ("ok", &check.ok),
+                ("unexpected_extra", &check.unexpected_extra),
+            ] {
+                lint_tree(
+                    problems,
+                    owner,
+                    &format!("{path}.ExpectSchedule.{name}"),
+                    branch,
+                );
+            }
+            return;
+        }
+        CheckerTree::MatchCheck(check) => (
+            "MatchCheck",
+            ["matched", "not_matched"],
+            usize::from(check.empty_ok == MatchEmpty::NotMatched),
+            [&check.matched, &check.not_matched],
+        ),
+        CheckerTree::DateCheck(check) => (
+            "DateCheck",
+            ["older_than", "younger_than"],
+            usize::from(check.empty_ok == DateEmpty::YoungerThan),
+            [&check.older_than, &check.younger_than],
+        ),
+        CheckerTree::BodyCheckAny(check) => {
+            if check.strings.is_empty() {
+                problems.push(format!("{owner}: the BodyCheckAny at {path} has no strings, so any mail that reaches it goes no further"));
+            }
+            if check.search == BodySearch::Server {
+                lint_search_strings(problems, owner, path, "BodyCheckAny", &check.strings);
+            }
+            (
+                "BodyCheckAny",
+                ["matched", "not_matched"],
+                usize::from(check.empty_ok == MatchEmpty::NotMatched),
+                [&check.matched, &check.not_matched],
+            )
+        }
+        CheckerTree::BodyCheckAll(check) => {
+            if check.strings.is_empty() {
+                problems.push(format!("{owner}: the BodyCheckAll at {path} has no strings, so any mail that reaches it goes no further"));
+            }
+            if check.search == BodySearch::Server {
+                lint_search_strings(problems, owner, path, "BodyCheckAll", &check.strings);
+            }
+            (
+                "BodyCheckAll",
+                ["matched", "not_matched"],
+                usize::from(check.empty_ok == MatchEmpty::NotMatched),
+                [&check.matched, &check.not_matched],
+            )
+        }
+        CheckerTree::ExtractCheck(check) => {
+            lint_tree(
+                problems,
+                owner,
+                &format!("{path}.ExtractCheck.not_found"),
+                &check.not_found,
+            );
+            (
+                "ExtractCheck",
+                ["matched", "not_matched"],
+                usize::from(check.empty_ok == MatchEmpty::NotMatched),
+                [&check.matched, &check.not_matched],
+            )
+        }
+        CheckerTree::BodyCheckRegex(check) => (
+            "BodyCheckRegex",
+            ["matched", "not_matched"],
+            usize::from(check.empty_ok == MatchEmpty::NotMatched),
+            [&check.matched, &check.not_matched],
+        ),
+        CheckerTree::AttachmentCheck(check) => (
+            "AttachmentCheck",
+            ["matched", "not_matched"],
+            usize::from(check.empty_ok == MatchEmpty::NotMatched),
+            [&check.matched, &check.not_matched],
+        ),
+    };
+
+    // The point of empty_ok is to get CountChecks run when there's no mail; if it points away
+    // from the only branch that has one, that CountCheck never gets to complain about there
+    // being no mail.
+    let other_branch = 1 - empty_branch;
+    if !counts_when_empty(branches[empty_branch]) && counts_when_empty(branches[other_branch]) {
+        problems.push(format!(
+            "{owner}: the {node} at {path} has empty_ok pointing at {}, so the CountCheck under {} never runs when there's no mail",
+            names[empty_branch], names[other_branch]
+        ));
+    }
+
+    for (name, branch) in names.iter().zip(branches) {
+        lint_tree(problems, owner, &format!("{path}.{node}.{name}"), branch);
+    }
+}
+
+// Whether running this tree against no mail at all gets as far as a CountCheck.
+fn counts_when_empty(tree: &CheckerTree) -> bool {
+    match tree {
+        CheckerTree::Stop | CheckerTree::Action(_) => false,
+        CheckerTree::MatchCheck(check) => counts_when_empty(match check.empty_ok {
+            MatchEmpty::Matched => &check.matched,
+            MatchEmpty::NotMatched => &check.not_matched,
+        }),
+        CheckerTree::DateCheck(check) => counts_when_empty(match check.empty_ok {
+            DateEmpty::OlderThan => &check.older_than,
+            DateEmpty::YoungerThan => &check.younger_than,
+        }),
+        CheckerTree::CountCheck(_)
+        | CheckerTree::CountRangeCheck(_)
+        | CheckerTree::RatioCheck(_) => true,
+        // No mail means no numbers
+        CheckerTree::AggregateCheck(check) => counts_when_empty(&check.not_found),
+        // Only the expected keys get a run when there's no mail
+        CheckerTree::GroupBy(check) => {
+            !check.expected_keys.is_empty() && counts_when_empty(&check.subtree)
+        }
+        CheckerTree::Correlate(check) => counts_when_empty(&check.paired),
+        CheckerTree::Latest(check) => counts_when_empty(&check.latest),
+        CheckerTree::DuplicateCheck(check) => counts_when_empty(&check.originals),
+        CheckerTree::SizeCheck(check) => counts_when_empty(&check.in_range),
+        // Depends on whether any runs were missed
+        CheckerTree::ExpectSchedule(check) => {
+            counts_when_empty(&check.ok) || counts_when_empty(&check.missing)
+        }
+        CheckerTree::BodyCheckAny(check) => counts_when_empty(match check.empty_ok {
+            MatchEmpty::Matched => &check.matched,
+            MatchEmpty::NotMatched => &check.not_matched,
+        }),
+        CheckerTree::BodyCheckAll(check) => counts_when_empty(match check.empty_ok {
+            MatchEmpty::Matched => &check.matched,
+            MatchEmpty::NotMatched => &check.not_matched,
+        }),
+        CheckerTree::ExtractCheck(check) => counts_when_empty(match check.empty_ok {
+            MatchEmpty::Matched => &check.matched,
+            MatchEmpty::NotMatched => &check.not_matched,
+        }),
+        CheckerTree::BodyCheckRegex(check) => counts_when_empty(match check.empty_ok {
+            MatchEmpty::Matched => &check.matched,
+            MatchEmpty::NotMatched => &check.not_matched,
+        }),
+        CheckerTree::AttachmentCheck(check) => counts_when_empty(match check.empty_ok {
+            MatchEmpty::Matched => &check.matched,
+            MatchEmpty::NotMatched => &check.not_matched,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod lint_test {
+    use super::lint;
+    use crate::configuration::{Account, Handler};
+
+    fn account(handlers: &str) -> Account {
+        Account {
+            name: "test".to_string(),
+            imapserver: "localhost".to_string(),
+            login: "login".to_string(),
+            password: secrecy::Secret::new("password".to_string()),
+            handlers: json5::from_str::<Vec<Handler>>(handlers).unwrap(),
+            source_folders: vec!["INBOX".to_string()],
+            storage_folder_name: "amcheck_storage".to_string(),
+            days_back: 60,
+            gmail_delete_hack: false,
+            uid_chunk_size: 1000,
+            envelope_cache_dir: None,
+        }
+    }
+
+    #[test]
+    fn test_clean() {
+        let account = account(
+            r#"[
+              {
+                name: "puppet",
+                filters: [ { Match: { Subject: "puppet" } } ],
+                checker_tree: {
+                  DateCheck: {
+                    days: 1,
+                    empty_ok: "YoungerThan",
+                    older_than: { Action: "Delete" },
+                    younger_than: {
+                      CountCheck: {
+                        count: 1,
+                        greater_than: { Action: "Success" },
+                        equal: { Action: "Success" },
+                        less_than: { Action: "Alert" },
+                      },
+                    },
+                  },
+                },
+              },
+              {
+                name: "puppet errors",
+                filters: [ { Match: { Subject: "puppet" } }, { Match: { From: "root" } } ],
+                source_folders: [ "cron" ],
+                storage_folder_name: "puppet_errors",
+                checker_tree: { Action: "Alert" },
+              },
+              {
+                name: "backups",
+                filters: [ { Match: { Subject: "backup" } } ],
+                checker_tree: {
+                  RatioCheck: {
+                    numerator: { BodyAny: [ "FAILED", "ERROR" ] },
+                    max_percent: 5,
+                    below: "Stop",
+                    in_range: {
+                      CountRangeCheck: {
+                        min: 5,
+                        max: 7,
+                        below: { Action: "Alert" },
+                        in_range: { Action: "Success" },
+                        above: { Action: "Alert" },
+                      },
+                    },
+                    above: { Action: "Alert" },
+                  },
+                },
+              },
+              {
+                name: "cron per host",
+                filters: [ { Match: { Subject: "^Cron <root@" } } ],
+                checker_tree: {
+                  GroupBy: {
+                    key: { field: "Subject", regex: "^Cron <root@(?<host>[^>]+)>" },
+                    expected_keys: [ "web1", "web2" ],
+                    subtree: {
+                      CountRangeCheck: {
+                        min: 1,
+                        below: { Action: "Alert" },
+                        in_range: { Action: "Success" },
+                        above: "Stop",
+                      },
+                    },
+                  },
+                },
+              },
+            ]"#,
+        );
+
+        assert_eq!(lint(&account), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_problems() {
+        let account = account(
+            r#"[
+              {
+                name: "puppet",
+                filters: [ { Match: { Subject: "puppet" } } ],
+                checker_tree: {
+                  DateCheck: {
+                    days: 1,
+                    empty_ok: "OlderThan",
+                    older_than: { Action: "Delete" },
+                    younger_than: {
+                      CountCheck: {
+                        count: 1,
+                        greater_than: { Action: "Success" },
+                        equal: { Action: "Success" },
+                        less_than: { Action: "Alert" },
+                      },
+                    },
+                  },
+                },
+              },
+              {
+                name: "puppet",
+                filters: [ { Match: { From: "root" } }, { Match: { Subject: "puppet" } } ],
+                storage_folder_name: "puppet",
+                checker_tree: {
+                  BodyCheckAny: {
+                    strings: [],
+                    empty_ok: "Matched",
+                    matched: { Action: "Success" },
+                    not_matched: { Action: "Alert" },
+                  },
+                },
+              },
+              {
+                name: "backups",
+                filters: [ { Match: { Subject: "backup" } } ],
+                checker_tree: {
+                  BodyCheckAll: {
+                    strings: [ "said \"done\"", "two\nlines" ],
+                    empty_ok: "Matched",
+                    matched: { Action: "Success" },
+                    not_matched: { Action: "Alert" },
+                  },
+                },
+              },
+            ]"#,
+        );
+
+        let problems = lint(&account);
+
+        assert_eq!(problems.len(), 5, "{problems:#?}");
+        assert!(
+            problems[0].contains("DateCheck at checker_tree has empty_ok pointing at older_than")
+        );
+        assert!(problems[1].contains("handler name 'puppet' is used more than once"));
+        assert!(problems[2].contains("will never see any mail in 'puppet'"));
+        assert!(problems[3].contains("BodyCheckAny at checker_tree has no strings"));
+        assert!(
+            problems[4].contains(r#"BodyCheckAll at checker_tree can't search for "two\nlines""#)
+        );
+    }
+
+    #[test]
+    fn test_search_strings() {
+        let account = account(
+            r#"[
+              {
+                name: "backups",
+                filters: [ { Match: { Subject: "backup" } } ],
+                checker_tree: {
+                  BodyCheckAny: {
+                    strings: [ "done", "Größe" ],
+                    empty_ok: "Matched",
+                    matched: { Action: "Success" },
+                    not_matched: {
+                      BodyCheckAny: {
+                        strings: [ "Größe" ],
+                        search: "SubstringIgnoreCase",
+                        empty_ok: "Matched",
+                        matched: { Action: "Success" },
+                        not_matched: { Action: "Alert" },
+                      },
+                    },
+                  },
+                },
+              },
+              {
+                name: "jobs",
+                filters: [ { Match: { Subject: "job" } } ],
+                checker_tree: {
+                  RatioCheck: {
+                    numerator: { BodyAny: [ "échec" ] },
+                    denominator: { BodyAll: [ "fine" ] },
+                    max_percent: 5,
+                    below: "Stop",
+                    in_range: { Action: "Success" },
+                    above: {
+                      Correlate: {
+                        start: { Matchers: [ { Match: { Subject: "started" } } ] },
+                        finish: { BodyAll: [ "two\nlines" ] },
+                        key: { field: "Subject", regex: "job (?<id>[0-9]+)" },
+                        timeout: "1h",
+                        paired: { Action: "Success" },
+                        timed_out: { Action: "Alert" },
+                      },
+                    },
+                  },
+                },
+              },
+            ]"#,
+        );
+
+        let problems = lint(&account);
+
+        assert_eq!(problems.len(), 3, "{problems:#?}");
+        assert!(problems[0].contains(
+            r#"BodyCheckAny at checker_tree searches the server for "Größe", which isn't ASCII"#
+        ));
+        assert!(problems[1].contains(
+            r#"RatioCheck at checker_tree searches the server for "échec", which isn't ASCII"#
+        ));
+        assert!(problems[2].contains(
+            r#"Correlate at checker_tree.RatioCheck.above can't search for "two\nlines""#
+        ));
+    }
+}